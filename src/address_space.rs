@@ -0,0 +1,619 @@
+use core::ops::Range;
+
+use alloc::{collections::BTreeMap, sync::Arc, vec, vec::Vec};
+use conquer_once::spin::OnceCell;
+use log::{debug, info, warn};
+
+use crate::{
+    csr, frame,
+    memory::{self, align_up, phys_to_virt, KERNEL_ASID},
+    paging::{self, MapFlags, PageSize, PageTable, PhysicalAddress, VirtualAddress},
+    percpu,
+    riscv::tlb,
+};
+
+static ASIDS: OnceCell<spin::Mutex<AsidAllocator>> = OnceCell::uninit();
+
+/// Reference counts of frames that are mapped by more than one address space,
+/// after a fork. Frames that aren't in the map have a single owner.
+static SHARED_FRAMES: spin::Mutex<BTreeMap<usize, usize>> = spin::Mutex::new(BTreeMap::new());
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+const PAGE_SIZE: usize = PageSize::Normal.size();
+
+/// Marks a page that is shared copy-on-write, in place of the write
+/// permission.
+const COPY_ON_WRITE: MapFlags = MapFlags::RSW_0;
+
+/// Allocator for address space identifiers, with the kernel's reserved.
+struct AsidAllocator {
+    /// One bit per ASID, set if it is in use.
+    bitmap: Vec<u64>,
+    /// Number of ASIDs the harts support.
+    count: usize,
+    /// Where to start looking for a free ASID, so that freed ASIDs aren't
+    /// reused straight away.
+    next: usize,
+}
+
+impl AsidAllocator {
+    fn new(count: usize) -> Self {
+        let mut bitmap = vec![0; align_up(count, BITS_PER_WORD) / BITS_PER_WORD];
+        bitmap[KERNEL_ASID / BITS_PER_WORD] |= 1 << (KERNEL_ASID % BITS_PER_WORD);
+        Self {
+            bitmap,
+            count,
+            next: 0,
+        }
+    }
+
+    fn alloc(&mut self) -> Option<usize> {
+        for i in 0..self.count {
+            let asid = (self.next + i) % self.count;
+            let word = &mut self.bitmap[asid / BITS_PER_WORD];
+            let bit = 1 << (asid % BITS_PER_WORD);
+            if *word & bit == 0 {
+                *word |= bit;
+                self.next = asid + 1;
+                return Some(asid);
+            }
+        }
+        None
+    }
+
+    fn free(&mut self, asid: usize) {
+        let word = &mut self.bitmap[asid / BITS_PER_WORD];
+        let bit = 1 << (asid % BITS_PER_WORD);
+        assert!(*word & bit != 0, "double free of ASID {}", asid);
+        *word &= !bit;
+    }
+}
+
+/// Find how many ASID bits the hart supports, by writing all of them to satp
+/// and seeing which stick.
+fn probe_asid_bits() -> u32 {
+    let satp = csr::Satp::read();
+    let mut probe = satp;
+    probe.set_asid(u16::MAX as u64);
+    // Only global kernel mappings are in use, so changing the ASID is harmless
+    probe.write();
+    let bits = csr::Satp::read().asid().count_ones();
+    satp.write();
+    bits
+}
+
+/// Set up the ASID allocator, sized by the ASIDs the boot hart supports.
+///
+/// The kernel's page table and heap must have been initialised.
+pub fn init() {
+    let bits = probe_asid_bits();
+    info!("{} ASID bits supported", bits);
+    ASIDS.init_once(|| spin::Mutex::new(AsidAllocator::new(1 << bits)));
+}
+
+fn asids() -> spin::MutexGuard<'static, AsidAllocator> {
+    ASIDS.get().expect("ASID allocator not initialised").lock()
+}
+
+/// The kind of access that caused a page fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Execute,
+    Load,
+    Store,
+}
+
+impl Access {
+    /// The permission a page needs for the access.
+    fn flag(self) -> MapFlags {
+        match self {
+            Access::Execute => MapFlags::EXECUTE,
+            Access::Load => MapFlags::READ,
+            Access::Store => MapFlags::WRITE,
+        }
+    }
+
+    /// Check whether a leaf with `flags` allows the access, for a trap taken
+    /// with `status`, in which case a fault on it came from a stale TLB entry.
+    fn allowed_by(self, flags: MapFlags, status: csr::Sstatus) -> bool {
+        let privileged = if flags.contains(MapFlags::USER) {
+            // The kernel can only load from and store to user pages with SUM
+            !status.spp() || (status.sum() && self != Access::Execute)
+        } else {
+            status.spp()
+        };
+        // Harts may fault rather than set the accessed and dirty bits
+        let tracked = flags.contains(MapFlags::ACCESSED)
+            && (self != Access::Store || flags.contains(MapFlags::DIRTY));
+        flags.contains(self.flag()) && privileged && tracked
+    }
+}
+
+/// What backs a virtual memory area.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaKind {
+    /// Zeroed memory, allocated a page at a time when it is first touched.
+    Anonymous,
+    /// Anonymous memory that grows down as far as `limit` when the memory below
+    /// it is touched.
+    Stack { limit: usize },
+}
+
+/// A range of an address space whose pages are mapped when they fault.
+#[derive(Debug, Clone)]
+pub struct Vma {
+    pub range: Range<usize>,
+    pub kind: VmaKind,
+    /// Flags every page in the area is mapped with.
+    pub flags: MapFlags,
+}
+
+/// The parts of an address space that change, behind its lock.
+struct Inner {
+    root: &'static mut PageTable,
+    /// Areas sorted by address, which don't overlap.
+    areas: Vec<Vma>,
+}
+
+impl Inner {
+    /// Find the index of the area a page is in, or of a stack that can grow
+    /// down to it.
+    fn area_for(&self, page: usize) -> Option<usize> {
+        if let Some(index) = self
+            .areas
+            .iter()
+            .position(|area| area.range.contains(&page))
+        {
+            return Some(index);
+        }
+
+        let index = self.areas.iter().position(|area| match area.kind {
+            VmaKind::Stack { limit } => (limit..area.range.start).contains(&page),
+            _ => false,
+        })?;
+        let start = self.areas[index].range.start;
+        if self
+            .areas
+            .iter()
+            .any(|other| other.range.start < start && other.range.end > page)
+        {
+            // Growing would run into another area
+            return None;
+        }
+        Some(index)
+    }
+
+    /// Check whether a range overlaps any area.
+    fn overlaps_area(&self, range: &Range<usize>) -> bool {
+        self.areas
+            .iter()
+            .any(|area| area.range.start < range.end && range.start < area.range.end)
+    }
+
+    /// Unmap every page in a range of anonymous memory, and release the frames.
+    fn release(&mut self, range: Range<usize>, asid: usize) {
+        let pages = mapped_pages(self.root, &range);
+        self.root
+            .unmap(range, asid, false, &mut frame::lock())
+            .expect("anonymous memory mapped with huge pages");
+        for (_, phys, _) in pages {
+            release_frame(phys);
+        }
+    }
+
+    /// Map every page in the areas into `child` as well, sharing the memory
+    /// copy-on-write.
+    ///
+    /// This address space uses `asid`, and the caller must shoot down its
+    /// entries on other harts afterwards, even if this fails.
+    fn share_with(&mut self, child: &mut Inner, asid: usize) -> Result<(), ()> {
+        for area in self.areas.clone() {
+            for (virt, phys, flags) in mapped_pages(self.root, &area.range) {
+                // Both copies lose write access until they write and fault
+                let shared_flags = if flags.contains(MapFlags::WRITE) {
+                    flags.difference(MapFlags::WRITE) | COPY_ON_WRITE
+                } else {
+                    flags
+                };
+                if shared_flags != flags {
+                    self.root
+                        .protect(virt..virt + PAGE_SIZE, shared_flags, asid)?;
+                }
+                child.root.map(
+                    VirtualAddress(virt as u64),
+                    PhysicalAddress(phys as u64),
+                    PageSize::Normal,
+                    shared_flags,
+                    &mut frame::lock(),
+                )?;
+                share_frame(phys);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A set of mappings in the lower half of the address space, alongside the
+/// kernel's mappings in the upper half.
+pub struct AddressSpace {
+    inner: spin::Mutex<Inner>,
+    root_ppn: usize,
+    /// The address space's own ASID, or None if they have run out, in which
+    /// case it uses the kernel's and the TLB is flushed on every switch to it.
+    asid: Option<usize>,
+}
+
+#[allow(dead_code)]
+impl AddressSpace {
+    /// Create an address space with nothing mapped in the lower half, and the
+    /// kernel's mappings cloned into the upper half.
+    pub fn new() -> Result<Arc<Self>, ()> {
+        let frame = frame::lock().alloc(0).ok_or(())?;
+        // # Safety
+        // The frame was just allocated, so nothing else is using it.
+        let root = unsafe { PageTable::new(frame) };
+        root.copy_kernel_half(&memory::kernel_table());
+
+        let asid = asids().alloc();
+        if asid.is_none() {
+            debug!("out of ASIDs, sharing the kernel's");
+        }
+        Ok(Arc::new(Self {
+            root_ppn: PageTable::ppn(root),
+            inner: spin::Mutex::new(Inner {
+                root,
+                areas: Vec::new(),
+            }),
+            asid,
+        }))
+    }
+
+    /// The ASID the address space's mappings are tagged with.
+    pub fn asid(&self) -> usize {
+        self.asid.unwrap_or(KERNEL_ASID)
+    }
+
+    /// The value of satp that switches to this address space.
+    pub fn satp(&self) -> csr::Satp {
+        let mut satp = memory::kernel_satp();
+        satp.set_asid(self.asid() as u64);
+        satp.set_ppn(self.root_ppn as u64);
+        satp
+    }
+
+    /// Check whether the current hart is using this address space.
+    pub fn is_current(&self) -> bool {
+        csr::Satp::read().ppn() == self.root_ppn as u64
+    }
+
+    /// Switch the current hart to this address space.
+    pub fn switch_to(self: &Arc<Self>) {
+        self.satp().write();
+        // Without an ASID of its own, entries left by any other address space
+        // that shared the kernel's ASID may still be cached
+        if self.asid.is_none() {
+            tlb::flush_asid(KERNEL_ASID);
+        }
+        percpu::current().set_address_space(Some(self.clone()));
+    }
+
+    /// Add an area of memory that is mapped on demand when it faults.
+    ///
+    /// Returns Err if the range isn't page aligned in the lower half, or it
+    /// overlaps another area.
+    pub fn add_area(&self, range: Range<usize>, kind: VmaKind, flags: MapFlags) -> Result<(), ()> {
+        let aligned = (range.start | range.end) & (PAGE_SIZE - 1) == 0;
+        let limit = match kind {
+            VmaKind::Stack { limit } => limit,
+            VmaKind::Anonymous => range.start,
+        };
+        if !aligned || range.is_empty() || limit > range.start || !is_lower_half(&range) {
+            warn!("invalid area {:X}-{:X}", range.start, range.end);
+            return Err(());
+        }
+        if !flags.is_valid_leaf() {
+            warn!("invalid leaf flags {:?}", flags);
+            return Err(());
+        }
+
+        let mut inner = self.inner.lock();
+        if inner.overlaps_area(&(limit..range.end)) {
+            warn!("area {:X}-{:X} overlaps another", range.start, range.end);
+            return Err(());
+        }
+        let index = inner
+            .areas
+            .iter()
+            .position(|area| area.range.start > range.start)
+            .unwrap_or(inner.areas.len());
+        inner.areas.insert(index, Vma { range, kind, flags });
+        Ok(())
+    }
+
+    /// Remove the area that starts at `start`, and free the memory in it.
+    pub fn remove_area(&self, start: usize) -> Result<(), ()> {
+        let mut inner = self.inner.lock();
+        let index = inner
+            .areas
+            .iter()
+            .position(|area| area.range.start == start)
+            .ok_or(())?;
+        let area = inner.areas.remove(index);
+        inner.release(area.range, self.asid());
+        Ok(())
+    }
+
+    /// Create a copy of the address space, sharing the memory in its areas
+    /// copy-on-write.
+    ///
+    /// Pages mapped with [`map`](Self::map) aren't copied.
+    pub fn fork(&self) -> Result<Arc<Self>, ()> {
+        let child = AddressSpace::new()?;
+        let mut inner = self.inner.lock();
+        let mut child_inner = child.inner.lock();
+        child_inner.areas = inner.areas.clone();
+
+        let result = inner.share_with(&mut child_inner, self.asid());
+        drop(child_inner);
+        // Other harts may still write to the shared frames through writable
+        // entries they cached from this address space
+        tlb::shootdown_asid(self.asid());
+
+        // If sharing failed part way, dropping the child unmaps the pages it
+        // was given and releases their references
+        result.map(|()| child)
+    }
+
+    /// Map a page of memory in the lower half of the address space, outside of
+    /// any area.
+    ///
+    /// The frame isn't owned by the address space, and isn't freed with it.
+    pub fn map(
+        &self,
+        virt: usize,
+        phys: usize,
+        page_size: PageSize,
+        flags: MapFlags,
+    ) -> Result<(), ()> {
+        let range = virt..virt + page_size.size();
+        let mut inner = self.inner.lock();
+        if !is_lower_half(&range) || inner.overlaps_area(&range) {
+            warn!("can't map {:X} outside of an area in the lower half", virt);
+            return Err(());
+        }
+        inner.root.map(
+            VirtualAddress(virt as u64),
+            PhysicalAddress(phys as u64),
+            page_size,
+            flags,
+            &mut frame::lock(),
+        )
+    }
+
+    /// Unmap a range of the lower half of the address space outside of any
+    /// area, without freeing the frames that were mapped.
+    pub fn unmap(&self, range: Range<usize>) -> Result<(), ()> {
+        let mut inner = self.inner.lock();
+        if !is_lower_half(&range) || inner.overlaps_area(&range) {
+            warn!(
+                "can't unmap {:X}-{:X} outside of an area in the lower half",
+                range.start, range.end
+            );
+            return Err(());
+        }
+        let asid = self.asid();
+        inner.root.unmap(range, asid, false, &mut frame::lock())
+    }
+
+    /// Resolve a page fault at `addr`, by mapping a zeroed frame, copying a
+    /// copy-on-write page or growing a stack.
+    ///
+    /// Returns Err if the access isn't allowed, or there is no memory left.
+    pub fn handle_fault(
+        &self,
+        addr: usize,
+        access: Access,
+        status: csr::Sstatus,
+    ) -> Result<(), ()> {
+        let page = addr & !(PAGE_SIZE - 1);
+        let mut inner = self.inner.lock();
+        let index = inner.area_for(page).ok_or(())?;
+        let area = &inner.areas[index];
+        if !area.flags.contains(access.flag()) {
+            return Err(());
+        }
+        // Set up front, as for the kernel's pages
+        let flags = area.flags | MapFlags::ACCESSED | MapFlags::DIRTY;
+        let grows = page < area.range.start;
+        let mut copied = false;
+
+        match inner.root.leaf_mut(VirtualAddress(page as u64)) {
+            None => {
+                let Some(frame) = frame::lock().alloc(0) else {
+                    warn!("out of memory for a page at {:X}", page);
+                    return Err(());
+                };
+                // # Safety
+                // The frame was just allocated, so nothing else is using it.
+                unsafe { (phys_to_virt(frame) as *mut u8).write_bytes(0, PAGE_SIZE) };
+                let result = inner.root.map(
+                    VirtualAddress(page as u64),
+                    PhysicalAddress(frame as u64),
+                    PageSize::Normal,
+                    flags,
+                    &mut frame::lock(),
+                );
+                if result.is_err() {
+                    frame::lock().free(frame, 0);
+                    return result;
+                }
+                // The stack only grows once the page is there
+                if grows {
+                    inner.areas[index].range.start = page;
+                }
+            }
+            Some((entry, _))
+                if access == Access::Store && entry.flags().contains(COPY_ON_WRITE) =>
+            {
+                let old = entry.as_physical_addr().unwrap().0 as usize;
+                if is_shared(old) {
+                    let Some(frame) = frame::lock().alloc(0) else {
+                        warn!("out of memory to copy a page at {:X}", page);
+                        return Err(());
+                    };
+                    // # Safety
+                    // The old frame stays mapped read-only until the copy has
+                    // been made, and the new frame isn't used by anything else.
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(
+                            phys_to_virt(old) as *const u8,
+                            phys_to_virt(frame) as *mut u8,
+                            PAGE_SIZE,
+                        );
+                    }
+                    // The other owners may have let go of the frame meanwhile,
+                    // in which case the copy isn't needed after all
+                    if release_shared(old) {
+                        entry.set_physical_addr(PhysicalAddress(frame as u64));
+                        copied = true;
+                    } else {
+                        frame::lock().free(frame, 0);
+                    }
+                }
+                entry.set_flags(flags);
+            }
+            Some((entry, _)) if access.allowed_by(entry.flags(), status) => {
+                // Another hart mapped the page since the fault was taken, or the
+                // TLB held a stale entry
+            }
+            Some(_) => return Err(()),
+        }
+
+        if copied {
+            // Other harts using the address space may still read the old
+            // frame, which its other owners are free to write
+            tlb::shootdown_asid(self.asid());
+        } else {
+            tlb::flush(page, self.asid());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Tear down the areas and page tables of the lower half, and release the
+    /// ASID.
+    ///
+    /// The address space can't be in use by any hart, as each hart keeps a
+    /// reference to the one it is using.
+    fn drop(&mut self) {
+        let asid = self.asid();
+        // Other harts may still have entries cached from when they last used it
+        tlb::shootdown_asid(asid);
+
+        let inner = self.inner.get_mut();
+        for area in core::mem::take(&mut inner.areas) {
+            inner.release(area.range, asid);
+        }
+        PageTable::free_user(inner.root, &mut frame::lock());
+
+        if let Some(asid) = self.asid {
+            asids().free(asid);
+        }
+    }
+}
+
+/// Switch the current hart back to only using the kernel's page table.
+#[allow(dead_code)]
+pub fn switch_to_kernel() {
+    memory::kernel_satp().write();
+    // Drop the reference only after the hart has stopped using the table
+    percpu::current().set_address_space(None);
+}
+
+/// Handle a page fault at `addr` in the address space the current hart is
+/// using.
+///
+/// Returns Err if the access isn't valid, in which case the fault is fatal.
+pub fn handle_page_fault(addr: usize, access: Access, status: csr::Sstatus) -> Result<(), ()> {
+    if !is_lower_half(&(addr..addr + 1)) {
+        return if is_stale_kernel_fault(addr, access, status) {
+            Ok(())
+        } else {
+            Err(())
+        };
+    }
+    match percpu::current().address_space() {
+        Some(space) => space.handle_fault(addr, access, status),
+        None => Err(()),
+    }
+}
+
+/// Check whether a fault in the kernel half of the address space was only
+/// because the hart cached the page from before another hart mapped it, in
+/// case the shootdown didn't reach it, and flush the stale entry if so.
+fn is_stale_kernel_fault(addr: usize, access: Access, status: csr::Sstatus) -> bool {
+    let Some(table) = PageTable::current() else {
+        return false;
+    };
+    let mut mapped = false;
+    table.walk(VirtualAddress(addr as u64), |_, entry| {
+        mapped = entry.is_leaf() && access.allowed_by(entry.flags(), status);
+    });
+    if mapped {
+        tlb::flush_global(addr);
+    }
+    mapped
+}
+
+/// Every normal page mapped in a range, as its virtual address, physical
+/// address and flags.
+fn mapped_pages(root: &PageTable, range: &Range<usize>) -> Vec<(usize, usize, MapFlags)> {
+    let mut pages = Vec::new();
+    root.mappings_in(range.clone(), |mapping| {
+        let start = mapping.virt.max(range.start);
+        let end = (mapping.virt + mapping.len).min(range.end);
+        for virt in (start..end).step_by(PAGE_SIZE) {
+            pages.push((virt, mapping.phys + (virt - mapping.virt), mapping.flags));
+        }
+    });
+    pages
+}
+
+/// Record another reference to a frame.
+fn share_frame(phys: usize) {
+    *SHARED_FRAMES.lock().entry(phys).or_insert(1) += 1;
+}
+
+fn is_shared(phys: usize) -> bool {
+    SHARED_FRAMES.lock().contains_key(&phys)
+}
+
+/// Drop a reference to a frame if it is shared, returning false if this was
+/// its only owner.
+fn release_shared(phys: usize) -> bool {
+    let mut shared = SHARED_FRAMES.lock();
+    match shared.get_mut(&phys) {
+        Some(count) => {
+            *count -= 1;
+            if *count == 1 {
+                shared.remove(&phys);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Drop a reference to a frame, freeing it if it was the last.
+fn release_frame(phys: usize) {
+    if !release_shared(phys) {
+        frame::lock().free(phys, 0);
+    }
+}
+
+/// Check whether a range is entirely in the lower half of the address space.
+fn is_lower_half(range: &Range<usize>) -> bool {
+    range.start <= range.end && range.end <= 1 << (paging::mode().virt_bits() - 1)
+}
@@ -1,27 +1,154 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use conquer_once::spin::OnceCell;
+use core::arch::asm;
 use core::mem;
 use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{info, log, warn, Level};
 
-/// The block sizes to use.
+use crate::{
+    cmdline::{parse_usize, Param, ParamSpec},
+    frame, heap_debug, memory,
+    paging::PageSize,
+    percpu,
+    slab::SlabCache,
+    smp, vmalloc,
+};
+
+/// The block sizes to use, each with its own slab cache.
 ///
 /// The sizes must each be power of 2 because they are also used as
 /// the block alignment (alignments must be always powers of 2).
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
-struct ListNode {
-    next: Option<&'static mut ListNode>,
-}
+/// Allocations at least this large are made from the vmalloc region rather
+/// than the heap, so they don't fragment it.
+const VMALLOC_THRESHOLD: usize = 64 * 1024;
+
+/// Number of return addresses recorded for each tracked allocation.
+const TRACK_DEPTH: usize = 4;
+
+/// Return addresses an allocation was made from, innermost first.
+pub type Callers = [usize; TRACK_DEPTH];
+
+/// Track the callers of up to this many live allocations, e.g.
+/// `heaptrack=4096`.
+static HEAP_TRACK: Param<usize> = Param::new("heaptrack", parse_usize);
+
+pub static PARAMS: &[&dyn ParamSpec] = &[&HEAP_TRACK];
 
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::uninit();
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+
+/// Allocations too large for any of the `BLOCK_SIZES` are made from the heap.
+static HEAP: Locked<HeapAllocator> = Locked::uninit();
 
-pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
-    fallback_allocator: linked_list_allocator::Heap,
+/// A slab cache for each of the `BLOCK_SIZES`.
+static CACHES: [SlabCache; BLOCK_SIZES.len()] = block_caches();
+
+/// Records the callers of live allocations, if enabled with `heaptrack`.
+static TRACKER: OnceCell<spin::Mutex<Tracker>> = OnceCell::uninit();
+
+/// Bytes of live allocations, rounded up to their block size.
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+/// Bytes of live allocations as requested.
+static REQUESTED: AtomicUsize = AtomicUsize::new(0);
+/// Largest `IN_USE` has been.
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+/// Bytes of `IN_USE` in vmalloc areas, rather than the heap or slabs.
+static VMALLOC_IN_USE: AtomicUsize = AtomicUsize::new(0);
+
+const fn block_caches() -> [SlabCache; BLOCK_SIZES.len()] {
+    // Only used to initialise the array, so it is never shared
+    #[allow(clippy::declare_interior_mutable_const)]
+    const UNUSED: SlabCache = SlabCache::new(0, 0);
+    let mut caches = [UNUSED; BLOCK_SIZES.len()];
+    let mut i = 0;
+    while i < BLOCK_SIZES.len() {
+        // The block sizes are also their alignment
+        caches[i] = SlabCache::new(BLOCK_SIZES[i], BLOCK_SIZES[i]);
+        i += 1;
+    }
+    caches
 }
 
-impl FixedSizeBlockAllocator {
+/// The kernel's global allocator, which serves small allocations from the slab
+/// caches and the rest from the heap.
+struct KernelAllocator;
+
+/// Allocator for the kernel heap, which grows as needed.
+pub struct HeapAllocator {
+    heap: linked_list_allocator::Heap,
+    allocs: u64,
+    frees: u64,
+}
+
+/// Usage statistics for one of the `BLOCK_SIZES` classes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClassStats {
+    pub allocs: u64,
+    pub frees: u64,
+    /// Number of free blocks in the class's slabs and magazines.
+    pub free_blocks: usize,
+    /// Number of slabs the class holds.
+    pub slabs: usize,
+}
+
+impl ClassStats {
+    /// Number of blocks of the class that are allocated.
+    ///
+    /// The counts are a snapshot taken while other harts allocate, so this is
+    /// only approximate.
+    pub fn live(&self) -> u64 {
+        self.allocs.saturating_sub(self.frees)
+    }
+}
+
+/// Usage statistics for the kernel's allocator.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    pub classes: [ClassStats; BLOCK_SIZES.len()],
+    /// Allocations too large for any class, made from the heap.
+    pub large_allocs: u64,
+    pub large_frees: u64,
+    /// Bytes of the heap that are handed out.
+    pub heap_used: usize,
+    /// Bytes of the heap that are mapped.
+    pub heap_size: usize,
+    /// Bytes of memory held by the classes' slabs.
+    pub slab_bytes: usize,
+    /// Bytes of live allocations, rounded up to their block size.
+    pub in_use: usize,
+    /// Bytes of live allocations as requested.
+    pub requested: usize,
+    /// Largest `in_use` has been.
+    pub peak: usize,
+    /// Bytes of `in_use` in vmalloc areas.
+    pub vmalloc_in_use: usize,
+}
+
+impl HeapStats {
+    /// Bytes of free blocks in the slabs, which only their class can reuse.
+    pub fn cached(&self) -> usize {
+        self.classes
+            .iter()
+            .zip(BLOCK_SIZES)
+            .map(|(class, size)| class.free_blocks * size)
+            .sum()
+    }
+
+    /// Percentage of the allocator's free memory that is stuck in slabs.
+    pub fn fragmentation(&self) -> usize {
+        let in_use = self.in_use.saturating_sub(self.vmalloc_in_use);
+        let free = (self.heap_size + self.slab_bytes).saturating_sub(in_use);
+        if free == 0 {
+            return 0;
+        }
+        self.cached() * 100 / free
+    }
+}
+
+impl HeapAllocator {
     /// Initialize the allocator with the given heap bounds.
     ///
     /// # Safety
@@ -29,20 +156,49 @@ impl FixedSizeBlockAllocator {
     /// heap bounds are valid and that the heap is unused. This method must be
     /// called only once.
     pub fn new(heap_start: *mut u8, heap_size: usize) -> Self {
-        const EMPTY: Option<&'static mut ListNode> = None;
-        FixedSizeBlockAllocator {
-            list_heads: [EMPTY; BLOCK_SIZES.len()],
-            fallback_allocator: unsafe { linked_list_allocator::Heap::new(heap_start, heap_size) },
+        HeapAllocator {
+            heap: unsafe { linked_list_allocator::Heap::new(heap_start, heap_size) },
+            allocs: 0,
+            frees: 0,
+        }
+    }
+
+    /// Allocates from the heap, growing it if it is full.
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = match self.heap.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => self.grow_and_alloc(layout),
+        };
+        if !ptr.is_null() {
+            self.allocs += 1;
         }
+        ptr
     }
 
-    /// Allocates using the fallback allocator.
-    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
+    fn grow_and_alloc(&mut self, layout: Layout) -> *mut u8 {
+        // Enough for the allocation wherever the free space at the top starts
+        let added = memory::grow_heap(layout.size() + layout.align());
+        if added == 0 {
+            return ptr::null_mut();
+        }
+        // # Safety
+        // The memory just after the top of the heap has been mapped, and nothing
+        // else uses it.
+        unsafe { self.heap.extend(added) };
+
+        match self.heap.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => ptr::null_mut(),
         }
     }
+
+    /// # Safety
+    /// The pointer must have been returned by `alloc` with the same layout.
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        self.frees += 1;
+        let ptr = NonNull::new(ptr).unwrap();
+        unsafe { self.heap.deallocate(ptr, layout) };
+    }
 }
 
 /// Choose an appropriate block size for the given layout.
@@ -53,53 +209,90 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
-unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+/// Check whether an allocation too large for the slab caches should be made
+/// from the vmalloc region, which only gives page alignment.
+fn use_vmalloc(layout: &Layout) -> bool {
+    layout.size() >= VMALLOC_THRESHOLD && layout.align() <= PageSize::Normal.size()
+}
+
+unsafe impl GlobalAlloc for KernelAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
-            Some(index) => {
-                match allocator.list_heads[index].take() {
-                    Some(node) => {
-                        allocator.list_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
-                    }
-                    None => {
-                        // no block exists in list => allocate new block
-                        let block_size = BLOCK_SIZES[index];
-                        // only works if all block sizes are a power of 2
-                        let block_align = block_size;
-                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                        allocator.fallback_alloc(layout)
-                    }
-                }
-            }
-            None => allocator.fallback_alloc(layout),
+        let block_layout = heap_debug::block_layout(layout);
+        let index = list_index(&block_layout);
+        let (block, block_size) = match index {
+            Some(index) => (CACHES[index].alloc(), BLOCK_SIZES[index]),
+            None if use_vmalloc(&block_layout) => (
+                vmalloc::vmalloc(block_layout.size()).map_or(ptr::null_mut(), NonNull::as_ptr),
+                memory::align_up(block_layout.size(), PageSize::Normal.size()),
+            ),
+            None => (HEAP.lock().alloc(block_layout), block_layout.size()),
+        };
+        if block.is_null() {
+            return block;
+        }
+
+        // Walk the stack here, so the allocator's own frames aren't recorded
+        let callers = if heap_debug::ENABLED || TRACKER.get().is_some() {
+            callers()
+        } else {
+            Callers::default()
+        };
+        // # Safety
+        // The block was just allocated with the debug layout. Blocks from a
+        // cache are always reused whole.
+        let slot = index.map(|index| BLOCK_SIZES[index]);
+        let ptr = unsafe { heap_debug::on_alloc(block, layout, slot, callers) };
+
+        if index.is_none() && use_vmalloc(&block_layout) {
+            VMALLOC_IN_USE.fetch_add(block_size, Ordering::Relaxed);
+        }
+        let in_use = IN_USE.fetch_add(block_size, Ordering::Relaxed) + block_size;
+        REQUESTED.fetch_add(layout.size(), Ordering::Relaxed);
+        PEAK.fetch_max(in_use, Ordering::Relaxed);
+        if let Some(tracker) = TRACKER.get() {
+            tracker.lock().insert(ptr as usize, layout.size(), callers);
         }
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let mut allocator = self.lock();
-        match list_index(&layout) {
+        let callers = if heap_debug::ENABLED {
+            callers()
+        } else {
+            Callers::default()
+        };
+        if let Some(tracker) = TRACKER.get() {
+            tracker.lock().remove(ptr as usize);
+        }
+        // # Safety
+        // The caller guarantees the pointer came from `alloc`.
+        let block = unsafe { heap_debug::on_free(ptr, layout, callers) };
+
+        let block_layout = heap_debug::block_layout(layout);
+        let block_size = match list_index(&block_layout) {
             Some(index) => {
-                let new_node = ListNode {
-                    next: allocator.list_heads[index].take(),
-                };
-                // verify that block has size and alignment required for storing node
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-                let new_node_ptr = ptr as *mut ListNode;
-                unsafe {
-                    new_node_ptr.write(new_node);
-                    allocator.list_heads[index] = Some(&mut *new_node_ptr);
-                }
+                // # Safety
+                // Allocations with this layout come from this cache.
+                unsafe { CACHES[index].free(block) };
+                BLOCK_SIZES[index]
+            }
+            None if use_vmalloc(&block_layout) => {
+                // # Safety
+                // Allocations with this layout come from vmalloc.
+                unsafe { vmalloc::vfree(NonNull::new(block).unwrap()) };
+                let block_size = memory::align_up(block_layout.size(), PageSize::Normal.size());
+                VMALLOC_IN_USE.fetch_sub(block_size, Ordering::Relaxed);
+                block_size
             }
             None => {
-                let ptr = NonNull::new(ptr).unwrap();
-                unsafe {
-                    allocator.fallback_allocator.deallocate(ptr, layout);
-                }
+                // # Safety
+                // Allocations with this layout come from the heap.
+                unsafe { HEAP.lock().dealloc(block, block_layout) };
+                block_layout.size()
             }
-        }
+        };
+        IN_USE.fetch_sub(block_size, Ordering::Relaxed);
+        REQUESTED.fetch_sub(layout.size(), Ordering::Relaxed);
     }
 }
 
@@ -121,8 +314,298 @@ impl<A> Locked<A> {
     pub fn lock(&self) -> spin::MutexGuard<A> {
         self.inner.get().unwrap().lock()
     }
+
+    /// Lock the allocator unless it is already locked, e.g. by the code an
+    /// interrupt arrived in.
+    pub fn try_lock(&self) -> Option<spin::MutexGuard<'_, A>> {
+        self.inner.get()?.try_lock()
+    }
+}
+
+pub fn init(func: impl FnOnce() -> HeapAllocator) {
+    HEAP.init(func);
+
+    if let Some(count) = HEAP_TRACK.get().filter(|count| *count > 0) {
+        match Tracker::new(count) {
+            Some(tracker) => {
+                TRACKER.init_once(|| spin::Mutex::new(tracker));
+                info!("tracking the callers of up to {} heap allocations", count);
+            }
+            None => warn!("out of memory for heap allocation tracking"),
+        }
+    }
+}
+
+/// Get the allocator's usage statistics.
+#[allow(dead_code)]
+pub fn stats() -> HeapStats {
+    let heap = HEAP.lock();
+    collect_stats(&heap)
+}
+
+fn collect_stats(heap: &HeapAllocator) -> HeapStats {
+    let mut stats = HeapStats {
+        large_allocs: heap.allocs,
+        large_frees: heap.frees,
+        heap_used: heap.heap.used(),
+        heap_size: heap.heap.size(),
+        in_use: IN_USE.load(Ordering::Relaxed),
+        requested: REQUESTED.load(Ordering::Relaxed),
+        peak: PEAK.load(Ordering::Relaxed),
+        vmalloc_in_use: VMALLOC_IN_USE.load(Ordering::Relaxed),
+        ..HeapStats::default()
+    };
+    for (class, cache) in stats.classes.iter_mut().zip(&CACHES) {
+        let cache = cache.stats();
+        *class = ClassStats {
+            allocs: cache.allocs,
+            frees: cache.frees,
+            free_blocks: cache.free_objects,
+            slabs: cache.slabs,
+        };
+        stats.slab_bytes += cache.slab_bytes;
+    }
+    stats
+}
+
+/// Log the allocator's usage statistics, with a line for each block size
+/// class.
+pub fn log_stats(level: Level) {
+    let Some(heap) = HEAP.try_lock() else {
+        log!(level, "heap is locked");
+        return;
+    };
+    let stats = collect_stats(&heap);
+    drop(heap);
+
+    log!(
+        level,
+        "allocator: {} KiB in use ({} KiB requested), peak {} KiB",
+        stats.in_use / 1024,
+        stats.requested / 1024,
+        stats.peak / 1024
+    );
+    log!(
+        level,
+        "{:>5} {:>10} {:>10} {:>8} {:>8} {:>6}",
+        "class",
+        "allocs",
+        "frees",
+        "live",
+        "free",
+        "slabs"
+    );
+    for (class, size) in stats.classes.iter().zip(BLOCK_SIZES) {
+        log!(
+            level,
+            "{:>5} {:>10} {:>10} {:>8} {:>8} {:>6}",
+            size,
+            class.allocs,
+            class.frees,
+            class.live(),
+            class.free_blocks,
+            class.slabs
+        );
+    }
+    log!(
+        level,
+        "{:>5} {:>10} {:>10} {:>8}",
+        "large",
+        stats.large_allocs,
+        stats.large_frees,
+        stats.large_allocs - stats.large_frees
+    );
+    log!(
+        level,
+        "heap {} KiB used of {} KiB mapped, slabs {} KiB with {} KiB free, {}% fragmentation",
+        stats.heap_used / 1024,
+        stats.heap_size / 1024,
+        stats.slab_bytes / 1024,
+        stats.cached() / 1024,
+        stats.fragmentation()
+    );
+    match vmalloc::try_stats() {
+        Some(vmalloc) => log!(
+            level,
+            "vmalloc {} areas of {} KiB",
+            vmalloc.areas,
+            vmalloc.bytes / 1024
+        ),
+        None => log!(level, "vmalloc is locked"),
+    }
+}
+
+/// Log every live allocation with the return addresses it was made from, which
+/// `addr2line` can turn into source locations.
+pub fn dump_live(level: Level) {
+    let Some(tracker) = TRACKER.get() else {
+        log!(
+            level,
+            "heap allocations aren't tracked, boot with heaptrack=<count>"
+        );
+        return;
+    };
+    let Some(tracker) = tracker.try_lock() else {
+        log!(level, "allocation tracker is locked");
+        return;
+    };
+    // The logger doesn't allocate, so it is fine to hold the lock
+    let mut total = 0;
+    for entry in tracker.entries.iter().filter(|entry| entry.ptr != 0) {
+        log!(
+            level,
+            "{:#018X} {:>8} bytes from {:X?}",
+            entry.ptr,
+            entry.size,
+            entry.callers
+        );
+        total += entry.size;
+    }
+    log!(
+        level,
+        "{} live allocations of {} bytes, {} more weren't tracked",
+        tracker.count,
+        total,
+        tracker.untracked
+    );
+}
+
+/// A live allocation and where it was made from.
+#[derive(Clone, Copy)]
+struct TrackedAlloc {
+    /// Address of the allocation, or 0 if the slot is empty.
+    ptr: usize,
+    size: usize,
+    callers: Callers,
+}
+
+/// Records the callers of live allocations, in a hash table keyed by address.
+///
+/// The table lives in frames of its own, as it can't allocate from the heap it
+/// is tracking.
+struct Tracker {
+    entries: &'static mut [TrackedAlloc],
+    count: usize,
+    /// Allocations that were made while the table was full.
+    untracked: usize,
+}
+
+impl Tracker {
+    /// Create a table for up to `count` allocations.
+    fn new(count: usize) -> Option<Self> {
+        // Leave a quarter of the slots empty to keep the probes short
+        let slots = count
+            .saturating_add(count / 3)
+            .checked_next_power_of_two()?;
+        let bytes = memory::align_up(
+            slots * mem::size_of::<TrackedAlloc>(),
+            PageSize::Normal.size(),
+        );
+        let pages = bytes / PageSize::Normal.size();
+        let order = pages.next_power_of_two().trailing_zeros() as usize;
+        if order > frame::MAX_ORDER {
+            return None;
+        }
+        let frame = frame::lock().alloc(order)?;
+
+        // # Safety
+        // The frames were just allocated, so nothing else uses them, and they
+        // are reached through the physmap.
+        let entries = unsafe {
+            core::slice::from_raw_parts_mut(memory::phys_to_virt(frame) as *mut TrackedAlloc, slots)
+        };
+        entries.fill(TrackedAlloc {
+            ptr: 0,
+            size: 0,
+            callers: [0; TRACK_DEPTH],
+        });
+        Some(Self {
+            entries,
+            count: 0,
+            untracked: 0,
+        })
+    }
+
+    fn slot(&self, ptr: usize) -> usize {
+        // Allocations are at least 8 byte aligned, so mix in the higher bits
+        ((ptr >> 3).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32) & (self.entries.len() - 1)
+    }
+
+    fn insert(&mut self, ptr: usize, size: usize, callers: Callers) {
+        if (self.count + 1) * 4 > self.entries.len() * 3 {
+            self.untracked += 1;
+            return;
+        }
+        let mask = self.entries.len() - 1;
+        let mut slot = self.slot(ptr);
+        while self.entries[slot].ptr != 0 {
+            slot = (slot + 1) & mask;
+        }
+        self.entries[slot] = TrackedAlloc { ptr, size, callers };
+        self.count += 1;
+    }
+
+    fn remove(&mut self, ptr: usize) {
+        let mask = self.entries.len() - 1;
+        let mut slot = self.slot(ptr);
+        loop {
+            match self.entries[slot].ptr {
+                // Allocated while the table was full or before tracking started
+                0 => return,
+                found if found == ptr => break,
+                _ => slot = (slot + 1) & mask,
+            }
+        }
+        self.count -= 1;
+
+        // Shift later entries of the probe back into the gap, so that lookups
+        // never stop early at it
+        let mut gap = slot;
+        let mut next = (gap + 1) & mask;
+        while self.entries[next].ptr != 0 {
+            let home = self.slot(self.entries[next].ptr);
+            // Move the entry unless its home slot is cyclically in (gap, next]
+            if (next.wrapping_sub(home) & mask) >= (next.wrapping_sub(gap) & mask) {
+                self.entries[gap] = self.entries[next];
+                gap = next;
+            }
+            next = (next + 1) & mask;
+        }
+        self.entries[gap].ptr = 0;
+    }
 }
 
-pub fn init(func: impl FnOnce() -> FixedSizeBlockAllocator) {
-    ALLOCATOR.init(func);
+/// Collect the return addresses of the function this is inlined into and its
+/// callers by following the frame pointers, stopping at the edge of the hart's
+/// stack.
+#[inline(always)]
+fn callers() -> Callers {
+    let mut callers = [0; TRACK_DEPTH];
+    let Some(cpu) = percpu::try_current() else {
+        return callers;
+    };
+    let stack_top = cpu.kernel_stack_top();
+    let stack_bottom = stack_top - smp::HART_STACK_SIZE;
+
+    let mut fp: usize;
+    // # Safety
+    // Only reads the frame pointer.
+    unsafe { asm!("mv {}, s0", out(reg) fp) };
+    for caller in callers.iter_mut() {
+        // The return address and the caller's frame pointer are saved just
+        // below the frame pointer
+        if fp & 7 != 0 || fp < stack_bottom + 16 || fp > stack_top {
+            break;
+        }
+        // # Safety
+        // The frame pointer is within the hart's stack, so this can be read.
+        let (ra, prev) = unsafe { (*(fp as *const usize).sub(1), *(fp as *const usize).sub(2)) };
+        *caller = ra;
+        // Frames go up the stack, stop if the chain doesn't
+        if prev <= fp {
+            break;
+        }
+        fp = prev;
+    }
+    callers
 }
@@ -1,11 +1,31 @@
 use core::arch::asm;
+use core::ops::RangeInclusive;
 
 use conquer_once::noblock::OnceCell;
 use fdt::Fdt;
-use log::debug;
+use log::{debug, warn};
+
+use crate::{
+    cmdline::{parse_usize, Param, ParamSpec},
+    percpu,
+};
 
 static CLINT: OnceCell<Clint> = OnceCell::uninit();
 
+/// Interval between timer ticks, unless overridden on the command line.
+pub const DEFAULT_TICK_NS: usize = 1_000_000_000;
+
+/// Interval between timer ticks in nanoseconds, e.g. `tick_ns=10000000`.
+pub static TICK_NS: Param<usize> = Param::new("tick_ns", parse_tick_ns);
+/// Range of tick intervals accepted on the command line.
+const TICK_NS_RANGE: RangeInclusive<usize> = 10_000..=60_000_000_000;
+
+pub static PARAMS: &[&dyn ParamSpec] = &[&TICK_NS];
+
+fn parse_tick_ns(value: &str) -> Option<usize> {
+    parse_usize(value).filter(|ns| TICK_NS_RANGE.contains(ns))
+}
+
 struct Clint {
     interval: usize,
 }
@@ -22,7 +42,15 @@ pub fn init(requested_interval_ns: usize, fdt: &Fdt) {
     debug!("using clint timebase frequency of {timebase_frequency:?} Hz");
 
     // Calculate how many counts is needed for the requested interval
-    let timebase_counts = (requested_interval_ns * timebase_frequency) / 1_000_000_000;
+    let timebase_counts = match requested_interval_ns.checked_mul(timebase_frequency) {
+        Some(product) => product / 1_000_000_000,
+        None => {
+            warn!(
+                "tick interval of {requested_interval_ns} ns is too long for the timebase, using {DEFAULT_TICK_NS} ns"
+            );
+            (DEFAULT_TICK_NS * timebase_frequency) / 1_000_000_000
+        }
+    };
     debug!("timebase counts {timebase_counts}");
 
     // Initialise the CLINT static
@@ -40,6 +68,7 @@ pub fn start() {
         asm!("csrr {}, time", out(reg) time);
     }
     let next_time = time + CLINT.try_get().unwrap().interval;
+    percpu::current().timer_deadline.set(next_time as u64);
 
     // Set timecmp to timebase_counts
     sbi::timer::set_timer(next_time as u64).unwrap();
@@ -0,0 +1,158 @@
+use conquer_once::spin::OnceCell;
+use log::warn;
+
+use crate::{allocator, clint, logger, memory};
+
+/// The kernel command line, from `/chosen/bootargs` in the FDT.
+static CMDLINE: OnceCell<&'static str> = OnceCell::uninit();
+
+/// Every parameter the kernel accepts, grouped by the subsystem that declares
+/// them.
+static PARAMS: &[&[&dyn ParamSpec]] = &[
+    crate::PARAMS,
+    logger::PARAMS,
+    memory::PARAMS,
+    allocator::PARAMS,
+    clint::PARAMS,
+];
+
+/// A parameter as far as validating the command line is concerned.
+pub trait ParamSpec: Sync {
+    /// Check whether this parameter accepts the given key.
+    fn matches(&self, key: &str) -> bool;
+
+    /// Check whether the given value is valid for this parameter.
+    fn is_valid(&self, value: Option<&'static str>) -> bool;
+}
+
+/// A parameter given as `name=value` on the command line.
+///
+/// If the parameter is given more than once, the last value is used.
+pub struct Param<T> {
+    name: &'static str,
+    parse: fn(&'static str) -> Option<T>,
+}
+
+impl<T> Param<T> {
+    pub const fn new(name: &'static str, parse: fn(&'static str) -> Option<T>) -> Self {
+        Self { name, parse }
+    }
+
+    /// Get the parameter's value, if it was given and is valid.
+    pub fn get(&self) -> Option<T> {
+        args()
+            .filter(|(key, _)| *key == self.name)
+            .last()
+            .and_then(|(_, value)| (self.parse)(value?))
+    }
+}
+
+impl<T> ParamSpec for Param<T> {
+    fn matches(&self, key: &str) -> bool {
+        key == self.name
+    }
+
+    fn is_valid(&self, value: Option<&'static str>) -> bool {
+        value.and_then(self.parse).is_some()
+    }
+}
+
+/// A family of parameters given as `prefix.name=value` on the command line.
+pub struct ParamFamily<T> {
+    prefix: &'static str,
+    parse: fn(&'static str) -> Option<T>,
+}
+
+impl<T> ParamFamily<T> {
+    pub const fn new(prefix: &'static str, parse: fn(&'static str) -> Option<T>) -> Self {
+        Self { prefix, parse }
+    }
+
+    /// Iterate over the valid members of the family that were given, as pairs
+    /// of the name after the prefix and the value.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, T)> + '_ {
+        args().filter_map(|(key, value)| {
+            let name = self.strip(key)?;
+            Some((name, (self.parse)(value?)?))
+        })
+    }
+
+    fn strip<'a>(&self, key: &'a str) -> Option<&'a str> {
+        key.strip_prefix(self.prefix)?
+            .strip_prefix('.')
+            .filter(|name| !name.is_empty())
+    }
+}
+
+impl<T> ParamSpec for ParamFamily<T> {
+    fn matches(&self, key: &str) -> bool {
+        self.strip(key).is_some()
+    }
+
+    fn is_valid(&self, value: Option<&'static str>) -> bool {
+        value.and_then(self.parse).is_some()
+    }
+}
+
+/// Store the kernel command line.
+pub fn init(cmdline: Option<&'static str>) {
+    CMDLINE.init_once(|| cmdline.unwrap_or(""));
+}
+
+/// The raw kernel command line.
+pub fn get() -> &'static str {
+    CMDLINE.get().copied().unwrap_or("")
+}
+
+/// Iterate over the arguments on the command line, as pairs of the key and
+/// the value if there was one.
+fn args() -> impl Iterator<Item = (&'static str, Option<&'static str>)> {
+    get()
+        .split_whitespace()
+        .map(|arg| match arg.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (arg, None),
+        })
+}
+
+/// Report any arguments on the command line that are unknown or malformed.
+pub fn report_invalid() {
+    for (key, value) in args() {
+        match PARAMS
+            .iter()
+            .flat_map(|p| p.iter())
+            .find(|p| p.matches(key))
+        {
+            Some(param) => {
+                if !param.is_valid(value) {
+                    warn!("malformed kernel parameter {:?}", key);
+                }
+            }
+            None => warn!("unknown kernel parameter {:?}", key),
+        }
+    }
+}
+
+/// Parse an unsigned integer, in decimal or in hex with a `0x` prefix.
+pub fn parse_usize(value: &str) -> Option<usize> {
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Parse a size in bytes, with an optional `K`, `M` or `G` suffix.
+pub fn parse_size(value: &str) -> Option<usize> {
+    let (number, shift) = match value.as_bytes().last()? {
+        b'K' | b'k' => (&value[..value.len() - 1], 10),
+        b'M' | b'm' => (&value[..value.len() - 1], 20),
+        b'G' | b'g' => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    parse_usize(number)?.checked_mul(1 << shift)
+}
+
+/// Parse a non-empty string.
+pub fn parse_str(value: &'static str) -> Option<&'static str> {
+    Some(value).filter(|v| !v.is_empty())
+}
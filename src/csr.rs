@@ -3,6 +3,7 @@ use core::arch::asm;
 use bitfield::bitfield;
 
 bitfield! {
+    #[derive(Clone, Copy)]
     pub struct Satp(u64);
     impl Debug;
 
@@ -26,3 +27,13 @@ impl Satp {
         }
     }
 }
+
+bitfield! {
+    /// The value of sstatus when a trap was taken.
+    #[derive(Clone, Copy)]
+    pub struct Sstatus(u64);
+    impl Debug;
+
+    pub spp, _: 8;
+    pub sum, _: 18;
+}
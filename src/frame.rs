@@ -0,0 +1,219 @@
+use conquer_once::spin::OnceCell;
+use log::info;
+
+use crate::{
+    memmap::MemoryMap,
+    memory::{align_up, phys_to_virt},
+    paging::PageSize,
+};
+
+static FRAME_ALLOCATOR: OnceCell<spin::Mutex<FrameAllocator>> = OnceCell::uninit();
+
+/// Largest order that can be allocated, i.e. a gigapage.
+pub const MAX_ORDER: usize = PageSize::Giga.order();
+
+const FRAME_SIZE: usize = PageSize::Normal.size();
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// Usage statistics for the frame allocator.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    /// Number of frames of usable memory.
+    pub total: usize,
+    /// Number of frames that are free to allocate.
+    pub free: usize,
+}
+
+impl FrameStats {
+    pub fn used(&self) -> usize {
+        self.total - self.free
+    }
+}
+
+/// Allocator for physical frames, tracking which are in use with a bitmap.
+///
+/// Allocations are of `2^order` contiguous frames, aligned to their size.
+pub struct FrameAllocator {
+    /// One bit per frame, set if the frame is in use or isn't usable memory.
+    bitmap: &'static mut [u64],
+    /// Physical address of the first frame in the bitmap.
+    base: usize,
+    /// Number of frames covered by the bitmap.
+    frames: usize,
+    /// No frame before this one is free.
+    search_start: usize,
+    stats: FrameStats,
+}
+
+impl FrameAllocator {
+    /// Create an allocator that manages every usable range of the memory map.
+    ///
+    /// The bitmap is stored at the start of the first usable range large
+    /// enough to hold it.
+    pub fn new(memory_map: &MemoryMap) -> Self {
+        let usable = memory_map.usable();
+        // Align the base so that frames aligned in the bitmap are physically
+        // aligned as well
+        let base = usable.iter().next().expect("no usable memory").start
+            & !((FRAME_SIZE << MAX_ORDER) - 1);
+        let end = usable.iter().map(|range| range.end).max().unwrap();
+        let frames = (end - base) / FRAME_SIZE;
+
+        let words = align_up(frames, BITS_PER_WORD) / BITS_PER_WORD;
+        let bitmap_size = align_up(words * core::mem::size_of::<u64>(), FRAME_SIZE);
+        let bitmap_start = usable
+            .iter()
+            .find(|range| range.len() >= bitmap_size)
+            .expect("no room for the frame bitmap")
+            .start;
+
+        // # Safety
+        // The range is usable memory, reached through the physmap, that is
+        // marked as used below so it is never handed out.
+        let bitmap = unsafe {
+            core::slice::from_raw_parts_mut(phys_to_virt(bitmap_start) as *mut u64, words)
+        };
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = Self {
+            bitmap,
+            base,
+            frames,
+            search_start: 0,
+            stats: FrameStats { total: 0, free: 0 },
+        };
+        for range in usable.iter() {
+            let count = range.len() / FRAME_SIZE;
+            allocator.set_range(allocator.frame_index(range.start), count, false);
+            allocator.stats.total += count;
+        }
+        allocator.set_range(
+            allocator.frame_index(bitmap_start),
+            bitmap_size / FRAME_SIZE,
+            true,
+        );
+        allocator.stats.free = allocator.stats.total - bitmap_size / FRAME_SIZE;
+
+        info!(
+            "frame allocator managing {} frames with a {} KiB bitmap",
+            allocator.stats.total,
+            bitmap_size / 1024
+        );
+
+        allocator
+    }
+
+    /// Allocate `2^order` contiguous frames, returning the physical address of
+    /// the first.
+    pub fn alloc(&mut self, order: usize) -> Option<usize> {
+        assert!(order <= MAX_ORDER, "frame allocation order too large");
+        let count = 1 << order;
+
+        let mut frame = align_up(self.search_start, count);
+        while frame + count <= self.frames {
+            // Skip over words that are entirely in use
+            if self.bitmap[frame / BITS_PER_WORD] == u64::MAX {
+                frame = align_up((frame / BITS_PER_WORD + 1) * BITS_PER_WORD, count);
+                continue;
+            }
+
+            match self.first_used(frame, count) {
+                Some(used) => frame = align_up(used + 1, count),
+                None => {
+                    self.set_range(frame, count, true);
+                    self.stats.free -= count;
+                    if frame <= self.search_start {
+                        self.search_start = frame + count;
+                    }
+                    return Some(self.base + frame * FRAME_SIZE);
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Free frames previously returned by [`alloc`](Self::alloc) with the same
+    /// order.
+    pub fn free(&mut self, addr: usize, order: usize) {
+        assert_eq!(addr % (FRAME_SIZE << order), 0, "misaligned frame freed");
+        let count = 1 << order;
+        let frame = self.frame_index(addr);
+        assert!(frame + count <= self.frames, "freed frame out of range");
+
+        for i in frame..frame + count {
+            if !self.is_used(i) {
+                panic!("double free of frame {:X}", self.base + i * FRAME_SIZE);
+            }
+        }
+
+        self.set_range(frame, count, false);
+        self.stats.free += count;
+        self.search_start = self.search_start.min(frame);
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    fn frame_index(&self, addr: usize) -> usize {
+        (addr - self.base) / FRAME_SIZE
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS_PER_WORD] & (1 << (frame % BITS_PER_WORD)) != 0
+    }
+
+    /// Find the first frame in use in the given run of frames.
+    fn first_used(&self, start: usize, count: usize) -> Option<usize> {
+        let end = start + count;
+        let mut frame = start;
+        while frame < end {
+            // Check a whole word at a time where possible
+            if frame & (BITS_PER_WORD - 1) == 0
+                && end - frame >= BITS_PER_WORD
+                && self.bitmap[frame / BITS_PER_WORD] == 0
+            {
+                frame += BITS_PER_WORD;
+                continue;
+            }
+
+            if self.is_used(frame) {
+                return Some(frame);
+            }
+            frame += 1;
+        }
+        None
+    }
+
+    fn set_range(&mut self, start: usize, count: usize, used: bool) {
+        for frame in start..start + count {
+            let word = &mut self.bitmap[frame / BITS_PER_WORD];
+            let bit = 1 << (frame % BITS_PER_WORD);
+            if used {
+                *word |= bit;
+            } else {
+                *word &= !bit;
+            }
+        }
+    }
+}
+
+/// Create the frame allocator from the memory map.
+pub fn init(memory_map: &MemoryMap) {
+    FRAME_ALLOCATOR.init_once(|| spin::Mutex::new(FrameAllocator::new(memory_map)));
+}
+
+/// Lock the frame allocator.
+pub fn lock() -> spin::MutexGuard<'static, FrameAllocator> {
+    FRAME_ALLOCATOR
+        .get()
+        .expect("frame allocator not initialised")
+        .lock()
+}
+
+/// Get the frame allocator's usage statistics.
+#[allow(dead_code)]
+pub fn stats() -> FrameStats {
+    lock().stats()
+}
@@ -0,0 +1,211 @@
+use alloc::alloc::Layout;
+use core::mem;
+
+use crate::allocator::Callers;
+
+/// Whether allocations are checked for heap corruption, with the `heap-debug`
+/// feature.
+///
+/// Each allocation is given a header recording where it was made from, with
+/// red zones on either side of it. Freeing an allocation checks the header and
+/// red zones, then fills the block with a poison pattern, which is checked
+/// again when a slab hands the same block out.
+pub const ENABLED: bool = cfg!(feature = "heap-debug");
+
+/// Bytes of guard before and after each allocation.
+const RED_ZONE: usize = 16;
+const RED_ZONE_BYTE: u8 = 0xCC;
+/// Freed blocks are filled with this.
+const POISON_BYTE: u8 = 0x6B;
+
+/// State of blocks in a slab that haven't been handed out yet.
+const UNUSED: u64 = 0;
+const ALLOCATED: u64 = 0xA110_CA7E_DB10_C000;
+const FREED: u64 = 0xF7EE_DB10_C000_DEAD;
+
+/// Bookkeeping at the start of each block.
+#[repr(C)]
+struct Header {
+    /// Left alone, as the allocators keep their own data here in free blocks.
+    _reserved: [usize; 2],
+    state: u64,
+    /// Layout the block was allocated with.
+    size: usize,
+    align: usize,
+    allocated_by: Callers,
+    freed_by: Callers,
+}
+
+/// Offset of an allocation from the start of its block.
+fn front(align: usize) -> usize {
+    (mem::size_of::<Header>() + RED_ZONE + align - 1) & !(align - 1)
+}
+
+fn block_align(align: usize) -> usize {
+    align.max(mem::align_of::<Header>())
+}
+
+/// Layout of the block that backs an allocation.
+pub fn block_layout(layout: Layout) -> Layout {
+    if !ENABLED {
+        return layout;
+    }
+    let align = block_align(layout.align());
+    Layout::from_size_align(front(align) + layout.size() + RED_ZONE, align)
+        .expect("allocation too large to debug")
+}
+
+/// Forget what the memory of a new slab block was used for before, so that a
+/// header left behind there isn't mistaken for the block's own.
+///
+/// # Safety
+/// The block must be `size` bytes that nothing else is using.
+pub unsafe fn on_new_block(block: *mut u8, size: usize) {
+    if !ENABLED || size < mem::size_of::<Header>() {
+        return;
+    }
+    // # Safety
+    // The header fits in the block, and the allocators' data in free blocks
+    // is kept clear of its state.
+    unsafe { (*(block as *mut Header)).state = UNUSED };
+}
+
+/// Set up a block that was just allocated, returning the pointer to hand out.
+///
+/// If `slot` is given, the block is a slab object of that many bytes, which is
+/// always reused at the same address, so the poison written when the block
+/// was last freed is checked.
+///
+/// # Safety
+/// The block must have just been allocated with [`block_layout`] of `layout`,
+/// and slab blocks must have been set up with [`on_new_block`].
+pub unsafe fn on_alloc(
+    block: *mut u8,
+    layout: Layout,
+    slot: Option<usize>,
+    callers: Callers,
+) -> *mut u8 {
+    if !ENABLED {
+        return block;
+    }
+    let header = block as *mut Header;
+    let offset = front(block_align(layout.align()));
+
+    // # Safety
+    // The block is large enough for the header, red zones and allocation.
+    unsafe {
+        let old = &*header;
+        // Only a block freed from the same cache can be found at the same
+        // address, any other header is a leftover
+        let old_size = match (slot, old.state) {
+            (Some(slot), FREED) => Some(front(block_align(old.align)) + old.size + RED_ZONE)
+                .filter(|&size| size <= slot),
+            _ => None,
+        };
+        if let Some(old_size) = old_size {
+            let poisoned = core::slice::from_raw_parts(
+                block.add(mem::size_of::<Header>()),
+                old_size - mem::size_of::<Header>(),
+            );
+            if let Some(written) = poisoned.iter().position(|byte| *byte != POISON_BYTE) {
+                let old_offset = front(block_align(old.align));
+                panic!(
+                    "use after free of {:#X} ({} bytes), written at offset {}, allocated from {:X?} and freed from {:X?}",
+                    block as usize + old_offset,
+                    old.size,
+                    (mem::size_of::<Header>() + written) as isize - old_offset as isize,
+                    old.allocated_by,
+                    old.freed_by
+                );
+            }
+        }
+
+        (*header).state = ALLOCATED;
+        (*header).size = layout.size();
+        (*header).align = layout.align();
+        (*header).allocated_by = callers;
+        (*header).freed_by = Callers::default();
+        let ptr = block.add(offset);
+        let before = block.add(mem::size_of::<Header>());
+        before.write_bytes(RED_ZONE_BYTE, ptr as usize - before as usize);
+        ptr.add(layout.size()).write_bytes(RED_ZONE_BYTE, RED_ZONE);
+        ptr
+    }
+}
+
+/// Check an allocation that is being freed and poison it, returning the block
+/// to give back to the allocator.
+///
+/// # Safety
+/// The pointer must have been returned by [`on_alloc`], though it may have
+/// been freed since or have been allocated with a different layout, which is
+/// what this checks.
+pub unsafe fn on_free(ptr: *mut u8, layout: Layout, callers: Callers) -> *mut u8 {
+    if !ENABLED {
+        return ptr;
+    }
+    let offset = front(block_align(layout.align()));
+    let block = ptr.wrapping_sub(offset);
+    let header = block as *mut Header;
+
+    // # Safety
+    // If the allocation came from `on_alloc` with this layout, the header and
+    // red zones are in the same block.
+    unsafe {
+        let allocation = &*header;
+        match allocation.state {
+            ALLOCATED => {}
+            FREED => panic!(
+                "double free of {:#X} ({} bytes) from {:X?}, allocated from {:X?} and first freed from {:X?}",
+                ptr as usize,
+                allocation.size,
+                callers,
+                allocation.allocated_by,
+                allocation.freed_by
+            ),
+            _ => panic!(
+                "free of {:#X}, which isn't a heap allocation or was freed with the wrong alignment, from {:X?}",
+                ptr as usize, callers
+            ),
+        }
+        if allocation.size != layout.size() || allocation.align != layout.align() {
+            panic!(
+                "{:#X} freed as {} bytes aligned to {} from {:X?}, but allocated as {} bytes aligned to {} from {:X?}",
+                ptr as usize,
+                layout.size(),
+                layout.align(),
+                callers,
+                allocation.size,
+                allocation.align,
+                allocation.allocated_by
+            );
+        }
+
+        let before = core::slice::from_raw_parts(
+            block.add(mem::size_of::<Header>()),
+            offset - mem::size_of::<Header>(),
+        );
+        let after = core::slice::from_raw_parts(ptr.add(layout.size()), RED_ZONE);
+        for (red_zone, side) in [(before, "before"), (after, "after")] {
+            if red_zone.iter().any(|byte| *byte != RED_ZONE_BYTE) {
+                panic!(
+                    "heap overflow {} {:#X} ({} bytes) found when freeing it from {:X?}, allocated from {:X?}",
+                    side,
+                    ptr as usize,
+                    layout.size(),
+                    callers,
+                    allocation.allocated_by
+                );
+            }
+        }
+
+        (*header).state = FREED;
+        (*header).freed_by = callers;
+        let poisoned = block.add(mem::size_of::<Header>());
+        poisoned.write_bytes(
+            POISON_BYTE,
+            block_layout(layout).size() - mem::size_of::<Header>(),
+        );
+    }
+    block
+}
@@ -0,0 +1,193 @@
+use core::ops::Range;
+
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use fdt::Fdt;
+use log::{debug, info, warn};
+
+use crate::memory::{align_up, phys_to_virt};
+
+static INITRAMFS: OnceCell<Initramfs> = OnceCell::uninit();
+
+/// Magic at the start of a cpio newc header, without and with checksums.
+const NEWC_MAGIC: &[u8] = b"070701";
+const NEWC_CRC_MAGIC: &[u8] = b"070702";
+/// Size of a cpio newc header, the magic followed by 13 hex fields.
+const HEADER_SIZE: usize = 110;
+/// Name of the entry that marks the end of the archive.
+const TRAILER: &str = "TRAILER!!!";
+
+/// File type bits of the mode.
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+const S_IFLNK: u32 = 0o120000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+/// A file in the initramfs.
+#[derive(Debug, Clone, Copy)]
+pub struct File {
+    /// Path of the file, without a leading slash.
+    pub path: &'static str,
+    pub mode: u32,
+    /// Contents of the file, or the target of a symlink.
+    pub data: &'static [u8],
+}
+
+impl File {
+    pub fn file_type(&self) -> FileType {
+        match self.mode & S_IFMT {
+            S_IFREG => FileType::File,
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            _ => FileType::Other,
+        }
+    }
+
+    /// Name of the file within its directory.
+    #[allow(dead_code)]
+    pub fn name(&self) -> &'static str {
+        self.path.rsplit('/').next().unwrap()
+    }
+}
+
+/// A read-only filesystem backed by a cpio newc archive in memory.
+pub struct Initramfs {
+    files: Vec<File>,
+}
+
+impl Initramfs {
+    /// Parse a cpio newc archive.
+    pub fn parse(archive: &'static [u8]) -> Result<Self, &'static str> {
+        let mut files = Vec::new();
+        let mut offset = 0;
+
+        loop {
+            let header = archive
+                .get(offset..offset + HEADER_SIZE)
+                .ok_or("truncated header")?;
+            if &header[..6] != NEWC_MAGIC && &header[..6] != NEWC_CRC_MAGIC {
+                return Err("bad header magic");
+            }
+
+            // Fields are 8 hex digits each, following the magic
+            let field = |index: usize| {
+                let start = 6 + 8 * index;
+                core::str::from_utf8(&header[start..start + 8])
+                    .ok()
+                    .and_then(|s| u32::from_str_radix(s, 16).ok())
+                    .ok_or("bad header field")
+            };
+            let mode = field(1)?;
+            let file_size = field(6)? as usize;
+            let name_size = field(11)? as usize;
+
+            // The name is NUL terminated, and padded so the data is 4-byte aligned
+            let name_start = offset + HEADER_SIZE;
+            let name = archive
+                .get(name_start..name_start + name_size)
+                .ok_or("truncated name")?;
+            let name = core::str::from_utf8(name.strip_suffix(&[0]).unwrap_or(name))
+                .map_err(|_| "name is not utf-8")?;
+
+            let data_start = align_up(name_start + name_size, 4);
+            let data = archive
+                .get(data_start..data_start + file_size)
+                .ok_or("truncated data")?;
+            offset = align_up(data_start + file_size, 4);
+
+            if name == TRAILER {
+                break;
+            }
+
+            let path = normalise(name);
+            if path.is_empty() {
+                // The root directory itself
+                continue;
+            }
+            files.push(File { path, mode, data });
+        }
+
+        Ok(Self { files })
+    }
+
+    /// Look up a file by its path.
+    pub fn open(&self, path: &str) -> Option<&File> {
+        let path = normalise(path);
+        self.files.iter().find(|file| file.path == path)
+    }
+
+    /// Iterate over the files directly inside a directory.
+    #[allow(dead_code)]
+    pub fn read_dir<'a>(&'a self, path: &'a str) -> impl Iterator<Item = &'a File> + 'a {
+        let dir = normalise(path);
+        self.files.iter().filter(move |file| {
+            let parent = file.path.rsplit_once('/').map_or("", |(parent, _)| parent);
+            parent == dir
+        })
+    }
+
+    /// Iterate over every file in the filesystem.
+    pub fn files(&self) -> impl Iterator<Item = &File> {
+        self.files.iter()
+    }
+}
+
+/// Strip the leading `./` or `/` and any trailing `/` from a path.
+fn normalise(path: &str) -> &str {
+    if path == "." {
+        return "";
+    }
+    let path = path.strip_prefix("./").unwrap_or(path);
+    path.trim_start_matches('/').trim_end_matches('/')
+}
+
+/// Find the physical address range of the initrd that the loader passed in the
+/// FDT, if there is one.
+pub fn find(fdt: &Fdt) -> Option<Range<usize>> {
+    let chosen = fdt.find_node("/chosen")?;
+    let start = chosen.property("linux,initrd-start")?.as_usize()?;
+    let end = chosen.property("linux,initrd-end")?.as_usize()?;
+    Some(start..end)
+}
+
+/// Parse the initrd as the initramfs.
+///
+/// The initrd range must have been reserved from the frame allocator, as the
+/// files are read from it in place.
+pub fn init(range: Range<usize>) {
+    info!("initrd at {:X} to {:X}", range.start, range.end);
+
+    // # Safety
+    // The range is reserved so that nothing else can write to it.
+    let archive =
+        unsafe { core::slice::from_raw_parts(phys_to_virt(range.start) as *const u8, range.len()) };
+
+    match Initramfs::parse(archive) {
+        Ok(initramfs) => {
+            for file in initramfs.files() {
+                debug!(
+                    "  /{} {:?} {} bytes",
+                    file.path,
+                    file.file_type(),
+                    file.data.len()
+                );
+            }
+            info!("initramfs has {} files", initramfs.files.len());
+            INITRAMFS.init_once(|| initramfs);
+        }
+        Err(e) => warn!("failed to parse initramfs: {}", e),
+    }
+}
+
+/// Get the initramfs, if one was loaded.
+pub fn get() -> Option<&'static Initramfs> {
+    INITRAMFS.get()
+}
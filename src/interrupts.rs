@@ -1,25 +1,36 @@
 use core::arch::asm;
 
-use log::{debug, warn};
+use log::{debug, warn, Level};
 
-use crate::{clint, plic, riscv::instructions::instruction_size};
+use crate::{
+    address_space::{self, Access},
+    allocator, clint, csr, logger, percpu, plic, ptdump,
+    riscv::instructions::instruction_size,
+};
 
 #[derive(Debug)]
 #[repr(C)]
-struct TrapContext {
+pub struct TrapContext {
     regs: [usize; 31],
     //fpu_regs: [usize; 32],
 }
 
-static mut TRAP_CONTEXT: TrapContext = TrapContext {
-    regs: [0; 31],
-    //fpu_regs: [0; 32],
-};
+impl TrapContext {
+    pub const fn new() -> Self {
+        Self {
+            regs: [0; 31],
+            //fpu_regs: [0; 32],
+        }
+    }
+}
 
+/// Initialise trap handling on the current hart.
+///
+/// The hart's per-cpu data must already be initialised.
 pub fn init() {
     // Put a location to store context in sscratch
     debug!("initialising trap scratch location");
-    let context = unsafe { &TRAP_CONTEXT as *const TrapContext };
+    let context = percpu::current().trap_context();
     unsafe {
         asm!(
             "csrw sscratch, {}",
@@ -48,6 +59,22 @@ pub fn init() {
     }
 }
 
+/// Run a closure with interrupts disabled on the current hart, restoring them
+/// afterwards if they were enabled.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let sstatus: usize;
+    // # Safety
+    // Clearing SIE only delays interrupts until it is set again.
+    unsafe { asm!("csrrci {}, sstatus, 0b10", out(reg) sstatus) };
+    let result = f();
+    if sstatus & 0b10 != 0 {
+        // # Safety
+        // Interrupts were enabled before.
+        unsafe { asm!("csrsi sstatus, 0b10") };
+    }
+    result
+}
+
 #[link_section = ".trap_handler"]
 #[no_mangle]
 #[naked]
@@ -90,6 +117,9 @@ extern "C" fn handler() -> ! {
             // save original x31 and restore original sscratch value
             "csrrw x30, sscratch, x31",
             "sd x30, 240(x31)",
+            // the trap context is at the start of the per-cpu data, so point
+            // tp at it in case we trapped from somewhere with a different tp
+            "mv tp, x31",
             // TODO: save floating point registers
             // TODO: look into making this pre-emptible, would need to save all the
             //       exception-related registers then re-enable higher-priority
@@ -138,7 +168,7 @@ extern "C" fn handler() -> ! {
             "ld x29, 224(x31)",
             "ld x30, 232(x31)",
             // restore x31, we don't need it after this
-            "ld x31, 232(x31)",
+            "ld x31, 240(x31)",
             // return from exception
             "sret",
             options(noreturn)
@@ -147,7 +177,15 @@ extern "C" fn handler() -> ! {
 }
 
 #[no_mangle]
-extern "C" fn dispatch(epc: usize, tval: usize, cause: usize, _status: usize) -> usize {
+extern "C" fn dispatch(epc: usize, tval: usize, cause: usize, status: usize) -> usize {
+    let cpu = percpu::current();
+    cpu.enter_interrupt();
+    let epc = handle_trap(epc, tval, cause, csr::Sstatus(status as u64));
+    cpu.exit_interrupt();
+    epc
+}
+
+fn handle_trap(epc: usize, tval: usize, cause: usize, status: csr::Sstatus) -> usize {
     let is_interrupt = cause >> 63 == 1;
     let cause = cause & !(1 << 63);
     // warn!(
@@ -171,8 +209,15 @@ extern "C" fn dispatch(epc: usize, tval: usize, cause: usize, _status: usize) ->
                 if let Some(id) = plic::claim() {
                     if id == 10 {
                         // UART
-                        let serial_char = unsafe { (0x1000_0000 as *const u8).read_volatile() };
-                        debug!("serial char: {serial_char}")
+                        match logger::read_byte() {
+                            // Debug command to dump the page table
+                            Some(b'p') => ptdump::dump_current(Level::Info),
+                            // Debug commands to show heap usage and live allocations
+                            Some(b'h') => allocator::log_stats(Level::Info),
+                            Some(b'l') => allocator::dump_live(Level::Info),
+                            Some(serial_char) => debug!("serial char: {serial_char}"),
+                            None => debug!("uart interrupt without a received byte"),
+                        }
                     } else {
                         warn!("unknown external interrupt {id}");
                     }
@@ -232,19 +277,20 @@ extern "C" fn dispatch(epc: usize, tval: usize, cause: usize, _status: usize) ->
             9 => {
                 warn!("ecall from s-mode");
             }
-            12 => {
-                panic!(
-                    "instruction page fault, epc=0x{:X} accessed=0x{:X}",
-                    epc, tval
-                );
-            }
-            13 => {
-                panic!("load page fault, epc=0x{:X} accessed=0x{:X}", epc, tval);
-            }
-            15 => {
+            12 | 13 | 15 => {
+                let access = match cause {
+                    12 => Access::Execute,
+                    13 => Access::Load,
+                    _ => Access::Store,
+                };
+                if address_space::handle_page_fault(tval, access, status).is_ok() {
+                    // Retry the instruction now that the page is mapped
+                    return epc;
+                }
+                ptdump::dump_walk(tval, Level::Error);
                 panic!(
-                    "store/amo page fault, epc=0x{:X} accessed=0x{:X}",
-                    epc, tval
+                    "{:?} page fault, epc=0x{:X} accessed=0x{:X}",
+                    access, epc, tval
                 );
             }
             _ => panic!("unhandled exception"),
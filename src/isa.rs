@@ -0,0 +1,35 @@
+use fdt::{standard_nodes::Cpu, Fdt};
+
+/// Check whether a hart supports a multi-letter ISA extension, such as
+/// `svpbmt`, according to its node in the FDT.
+pub fn hart_has_extension(cpu: &Cpu, extension: &str) -> bool {
+    // Newer FDTs list each extension separately
+    if let Some(extensions) = cpu.property("riscv,isa-extensions") {
+        return extensions
+            .value
+            .split(|&b| b == 0)
+            .any(|name| name.eq_ignore_ascii_case(extension.as_bytes()));
+    }
+
+    // Otherwise multi-letter extensions follow the base ISA string, separated
+    // by underscores, e.g. `rv64imafdc_zicsr_svpbmt`
+    cpu.property("riscv,isa")
+        .and_then(|p| p.as_str())
+        .is_some_and(|isa| {
+            isa.split('_')
+                .skip(1)
+                .any(|name| name.eq_ignore_ascii_case(extension))
+        })
+}
+
+/// Check whether every usable hart supports a multi-letter ISA extension.
+pub fn all_harts_have_extension(fdt: &Fdt, extension: &str) -> bool {
+    fdt.cpus()
+        .filter(|cpu| {
+            cpu.property("status")
+                .and_then(|p| p.as_str())
+                .unwrap_or("okay")
+                == "okay"
+        })
+        .all(|cpu| hart_has_extension(&cpu, extension))
+}
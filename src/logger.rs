@@ -1,9 +1,17 @@
 use core::fmt::Write;
+use core::mem;
 
 use conquer_once::spin::OnceCell;
-use log::Level;
+use log::{Level, LevelFilter};
 use uart_16550::MmioSerialPort;
 
+use crate::{
+    cmdline::{Param, ParamFamily, ParamSpec},
+    memory,
+    mmio::{IoMapping, ReadOnly, ReadWrite, RegisterBlock, Reserved},
+    percpu,
+};
+
 const RESET: &str = "\x1B[0m";
 const SUBTLE: &str = "\x1B[30;1m";
 
@@ -19,28 +27,125 @@ fn get_colour(level: log::Level) -> &'static str {
 
 static LOGGER: OnceCell<Logger> = OnceCell::uninit();
 
+/// The console UART's registers, once they have been mapped.
+static UART: OnceCell<IoMapping> = OnceCell::uninit();
+
+/// The registers of a 16550 UART, as far as receiving is concerned.
+#[repr(C)]
+struct UartRegisters {
+    /// The received byte when read.
+    data: ReadWrite<u8>,
+    _reserved: Reserved<4>,
+    line_status: ReadOnly<u8>,
+    _reserved2: Reserved<2>,
+}
+
+unsafe impl RegisterBlock for UartRegisters {}
+
+const LINE_STATUS_DATA_READY: u8 = 1;
+
+/// The maximum level to log at, e.g. `loglevel=info`.
+static LOG_LEVEL: Param<LevelFilter> = Param::new("loglevel", parse_level);
+/// The maximum level to log at for a module, e.g. `log.paging=trace`.
+static MODULE_LOG_LEVEL: ParamFamily<LevelFilter> = ParamFamily::new("log", parse_level);
+
+pub static PARAMS: &[&dyn ParamSpec] = &[&LOG_LEVEL, &MODULE_LOG_LEVEL];
+
+/// Maximum number of modules that can have their own log level.
+const MAX_MODULE_LEVELS: usize = 8;
+
+fn parse_level(value: &str) -> Option<LevelFilter> {
+    value.parse().ok()
+}
+
 pub fn init(uart_addr: *const u8) {
+    // Collect the per-module log levels from the command line
+    let mut module_levels = [None; MAX_MODULE_LEVELS];
+    for (slot, level) in module_levels.iter_mut().zip(MODULE_LOG_LEVEL.iter()) {
+        *slot = Some(level);
+    }
+    let level = LOG_LEVEL.get().unwrap_or(LevelFilter::Trace);
+
     // Initialise UART
     LOGGER.init_once(|| unsafe {
-        let mut uart = MmioSerialPort::new(uart_addr as usize);
+        // Devices are reached through the physmap
+        let mut uart = MmioSerialPort::new(memory::phys_to_virt(uart_addr as usize));
         uart.init();
         Logger {
             uart: spin::Mutex::new(uart),
+            uart_addr: uart_addr as usize,
+            level,
+            module_levels,
         }
     });
 
-    // Initialise logger
+    // Initialise logger, letting through anything a module might want to log
+    let max_level = module_levels
+        .iter()
+        .flatten()
+        .map(|&(_, level)| level)
+        .fold(level, Ord::max);
     log::set_logger(LOGGER.get().unwrap()).unwrap();
-    log::set_max_level(log::LevelFilter::Trace);
+    log::set_max_level(max_level);
+
+    if MODULE_LOG_LEVEL.iter().count() > MAX_MODULE_LEVELS {
+        log::warn!(
+            "only the first {} module log levels are used",
+            MAX_MODULE_LEVELS
+        );
+    }
+}
+
+/// Move the console UART from the physmap to a mapping of its own with IO
+/// attributes, once the kernel's page table is set up.
+pub fn map_uart() {
+    let logger = LOGGER.get().unwrap();
+    let registers = IoMapping::new(logger.uart_addr, mem::size_of::<UartRegisters>())
+        .expect("no room to map the console");
+    // # Safety
+    // This is the same UART, which has already been initialised.
+    *logger.uart.lock() = unsafe { MmioSerialPort::new(registers.base()) };
+    UART.init_once(|| registers);
+}
+
+/// Read a byte the console UART has received, if there is one.
+pub fn read_byte() -> Option<u8> {
+    let registers = UART.get()?.block::<UartRegisters>(0);
+    if registers.line_status.read() & LINE_STATUS_DATA_READY != 0 {
+        Some(registers.data.read())
+    } else {
+        None
+    }
 }
 
 struct Logger {
     uart: spin::Mutex<MmioSerialPort>,
+    /// Physical address of the UART's registers.
+    uart_addr: usize,
+    level: LevelFilter,
+    module_levels: [Option<(&'static str, LevelFilter)>; MAX_MODULE_LEVELS],
+}
+
+impl Logger {
+    /// Get the level to log at for a target, such as `annex::paging`.
+    fn level_for(&self, target: &str) -> LevelFilter {
+        // Module names are given without the crate name
+        let module = target.split_once("::").map_or("", |(_, module)| module);
+        self.module_levels
+            .iter()
+            .flatten()
+            .find(|(name, _)| {
+                module
+                    .strip_prefix(name)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map_or(self.level, |&(_, level)| level)
+    }
 }
 
 impl log::Log for Logger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
     }
 
     fn log(&self, record: &log::Record) {
@@ -48,14 +153,18 @@ impl log::Log for Logger {
             return;
         }
 
+        // Harts that haven't set up their per-cpu data yet show up as '-'
+        let hart = percpu::try_current().map(|cpu| cpu.hart_id());
+
         writeln!(
             self.uart.lock(),
-            "{}[{}{}{:<5}{} {}:{}{}]{} {}",
+            "{}[{}{}{:<5}{} {} {}:{}{}]{} {}",
             SUBTLE,
             RESET,
             get_colour(record.level()),
             record.level(),
             RESET,
+            HartId(hart),
             record
                 .file()
                 .unwrap_or("UNKNOWN")
@@ -70,3 +179,15 @@ impl log::Log for Logger {
 
     fn flush(&self) {}
 }
+
+/// Formats an optional hart id for the log prefix.
+struct HartId(Option<usize>);
+
+impl core::fmt::Display for HartId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 {
+            Some(hart) => write!(f, "{}", hart),
+            None => write!(f, "-"),
+        }
+    }
+}
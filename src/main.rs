@@ -14,32 +14,68 @@ extern crate alloc;
 
 use core::arch::{asm, global_asm};
 
+use cmdline::{parse_str, Param, ParamSpec};
 use fdt::Fdt;
 use log::{debug, error, info, warn};
 use sbi::system_reset::{ResetReason, ResetType};
 
+mod address_space;
 mod allocator;
 mod clint;
+mod cmdline;
 mod csr;
+mod frame;
+mod heap_debug;
+mod initramfs;
 mod interrupts;
+mod isa;
 mod logger;
+mod memmap;
 mod memory;
+mod mmio;
 mod paging;
 mod panic;
+mod percpu;
 mod plic;
+mod ptdump;
 mod riscv;
+mod slab;
+mod smp;
+mod vmalloc;
+
+/// The console to log to, as a path or alias in the FDT, e.g.
+/// `console=/soc/serial@10000000`.
+static CONSOLE: Param<&'static str> = Param::new("console", parse_str);
+/// The program to run as the first process, e.g. `init=/bin/init`.
+pub static INIT: Param<&'static str> = Param::new("init", parse_str);
+
+pub static PARAMS: &[&dyn ParamSpec] = &[&CONSOLE, &INIT];
+
+#[no_mangle]
+pub extern "C" fn kmain(hart_id: usize, fdt_addr: usize, stack_top: usize) -> ! {
+    percpu::init(hart_id, stack_top);
+
+    // Keep using the physmap to reach the FDT once the identity map is gone
+    let fdt = unsafe { Fdt::from_ptr(memory::phys_to_virt(fdt_addr) as *const u8).unwrap() };
+
+    entrypoint(hart_id, fdt_addr, fdt);
+}
 
 #[no_mangle]
-pub extern "C" fn kmain(hart_id: usize, fdt_addr: usize) -> ! {
-    let fdt = unsafe { Fdt::from_ptr(fdt_addr as *const u8).unwrap() };
+pub extern "C" fn kmain_secondary(hart_id: usize, _opaque: usize, stack_top: usize) -> ! {
+    percpu::init(hart_id, stack_top);
 
-    entrypoint(hart_id, fdt);
+    secondary_entrypoint(hart_id);
 }
 
-fn entrypoint(hart_id: usize, fdt: Fdt) -> ! {
+fn entrypoint(hart_id: usize, fdt_addr: usize, fdt: Fdt<'static>) -> ! {
+    cmdline::init(fdt.chosen().bootargs());
+
     logger::init(
-        fdt.chosen()
-            .stdout()
+        CONSOLE
+            .get()
+            .and_then(|console| fdt.find_node(console))
+            .or_else(|| fdt.chosen().stdout())
             .or_else(|| fdt.find_node("/soc/uart"))
             .unwrap()
             .reg()
@@ -48,22 +84,63 @@ fn entrypoint(hart_id: usize, fdt: Fdt) -> ! {
             .unwrap()
             .starting_address,
     );
-    memory::init(fdt.memory().regions());
+    cmdline::report_invalid();
+    if let Some(console) = CONSOLE.get().filter(|c| fdt.find_node(c).is_none()) {
+        warn!("console {:?} not found, using the default", console);
+    }
+    let initrd = initramfs::find(&fdt);
+    let memory_map = memmap::init(&fdt, fdt_addr, initrd.clone());
+    paging::detect_extensions(&fdt);
+    memory::init(memory_map, paging::PagingMode::max_supported(&fdt));
+    logger::map_uart();
+    address_space::init();
+    if let Some(initrd) = initrd {
+        initramfs::init(initrd);
+    }
 
     info!("booting ANNEX kernel");
     debug!("currently running on hart {}", hart_id);
-
-    debug!("hart status:");
-    for hart in fdt.cpus().flat_map(|cpu| cpu.ids().all()) {
-        debug!("  {}: {:?}", hart, sbi::hsm::hart_status(hart).unwrap());
+    debug!("kernel command line {:?}", cmdline::get());
+    if let Some(init) = INIT.get() {
+        match initramfs::get().and_then(|fs| fs.open(init)) {
+            Some(file) => debug!("init program {} is {} bytes", init, file.data.len()),
+            None => warn!("init program {} not found", init),
+        }
     }
 
     interrupts::init();
-    clint::init(1_000_000_000, &fdt);
+    clint::init(clint::TICK_NS.get().unwrap_or(clint::DEFAULT_TICK_NS), &fdt);
     clint::start();
     plic::init(&fdt);
+    plic::init_hart();
+    plic::set_enable(hart_id, 0x0A, true);
 
-    abort();
+    smp::start_secondary_harts(hart_id, &fdt);
+
+    kernel_loop();
+}
+
+fn secondary_entrypoint(hart_id: usize) -> ! {
+    memory::init_hart();
+    debug!("hart {} online", hart_id);
+
+    interrupts::init();
+    clint::start();
+    plic::init_hart();
+
+    smp::hart_online();
+
+    kernel_loop();
+}
+
+/// The loop that every hart settles into once it has been initialised.
+fn kernel_loop() -> ! {
+    percpu::current().set_scheduler_state(percpu::SchedulerState::Idle);
+    loop {
+        unsafe {
+            asm!("wfi");
+        }
+    }
 }
 
 #[allow(dead_code)]
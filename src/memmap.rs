@@ -0,0 +1,279 @@
+use core::fmt;
+use core::ops::Range;
+
+use conquer_once::spin::OnceCell;
+use fdt::Fdt;
+use log::info;
+
+use crate::{memory, paging::PageSize};
+
+static MEMORY_MAP: OnceCell<MemoryMap> = OnceCell::uninit();
+
+/// Maximum number of ranges in a [`RangeSet`].
+const MAX_RANGES: usize = 32;
+/// Maximum number of reserved ranges in the memory map.
+const MAX_RESERVED: usize = 32;
+
+/// A sorted set of non-overlapping address ranges.
+///
+/// This has a fixed capacity, as it is needed before the heap exists.
+pub struct RangeSet {
+    ranges: [Range<usize>; MAX_RANGES],
+    len: usize,
+}
+
+impl RangeSet {
+    pub const fn new() -> Self {
+        const EMPTY: Range<usize> = 0..0;
+        Self {
+            ranges: [EMPTY; MAX_RANGES],
+            len: 0,
+        }
+    }
+
+    /// Add a range to the set, merging it with any ranges it overlaps or
+    /// touches.
+    pub fn insert(&mut self, mut range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+
+        let mut i = 0;
+        while i < self.len {
+            let existing = &self.ranges[i];
+            if existing.start <= range.end && range.start <= existing.end {
+                range = existing.start.min(range.start)..existing.end.max(range.end);
+                self.remove_index(i);
+            } else {
+                i += 1;
+            }
+        }
+
+        // Keep the ranges sorted by address
+        let index = self
+            .iter()
+            .position(|existing| existing.start > range.start)
+            .unwrap_or(self.len);
+        self.insert_index(index, range);
+    }
+
+    /// Remove a range from the set, splitting any range it is in the middle of.
+    pub fn remove(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+
+        let old = core::mem::replace(self, Self::new());
+        for existing in old.iter() {
+            // The set is sorted, so pushing the pieces in order keeps it sorted
+            self.push(existing.start..existing.end.min(range.start));
+            self.push(existing.start.max(range.end)..existing.end);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Range<usize>> {
+        self.ranges[..self.len].iter()
+    }
+
+    /// Total size of all the ranges in bytes.
+    pub fn size(&self) -> usize {
+        self.iter().map(|range| range.len()).sum()
+    }
+
+    /// Check whether the whole of `range` is within one range of the set.
+    pub fn contains(&self, range: &Range<usize>) -> bool {
+        self.iter()
+            .any(|existing| existing.start <= range.start && range.end <= existing.end)
+    }
+
+    fn push(&mut self, range: Range<usize>) {
+        if !range.is_empty() {
+            self.insert_index(self.len, range);
+        }
+    }
+
+    fn insert_index(&mut self, index: usize, range: Range<usize>) {
+        if self.len == MAX_RANGES {
+            panic!("too many physical memory ranges");
+        }
+        self.ranges[index..=self.len].rotate_right(1);
+        self.ranges[index] = range;
+        self.len += 1;
+    }
+
+    fn remove_index(&mut self, index: usize) {
+        self.ranges[index..self.len].rotate_left(1);
+        self.len -= 1;
+    }
+}
+
+/// Why a range of physical memory can't be used by the kernel.
+#[derive(Debug, Clone, Copy)]
+pub enum Reservation {
+    /// An entry in the FDT's memory reservation block.
+    MemReserve,
+    /// A child of `/reserved-memory`, with the name of the node.
+    ReservedMemory(&'static str),
+    Kernel,
+    Fdt,
+    Initrd,
+}
+
+impl fmt::Display for Reservation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reservation::MemReserve => write!(f, "memreserve"),
+            Reservation::ReservedMemory(name) => write!(f, "reserved-memory {}", name),
+            Reservation::Kernel => write!(f, "kernel"),
+            Reservation::Fdt => write!(f, "device tree"),
+            Reservation::Initrd => write!(f, "initrd"),
+        }
+    }
+}
+
+/// The layout of physical memory.
+pub struct MemoryMap {
+    /// Every range of RAM described by the FDT.
+    ram: RangeSet,
+    /// Ranges of RAM that must not be given out.
+    reserved: [Option<(Range<usize>, Reservation)>; MAX_RESERVED],
+    /// RAM that is free for the kernel to use, in whole pages.
+    usable: RangeSet,
+}
+
+impl MemoryMap {
+    /// Build the memory map from every memory node in the FDT, minus reserved
+    /// ranges and the memory the kernel is already using.
+    pub fn from_fdt(fdt: &Fdt<'static>, fdt_addr: usize, initrd: Option<Range<usize>>) -> Self {
+        const EMPTY: Option<(Range<usize>, Reservation)> = None;
+        let mut map = Self {
+            ram: RangeSet::new(),
+            reserved: [EMPTY; MAX_RESERVED],
+            usable: RangeSet::new(),
+        };
+
+        // There can be any number of memory nodes, each with several regions
+        let memory_nodes = fdt
+            .all_nodes()
+            .filter(|node| node.property("device_type").and_then(|p| p.as_str()) == Some("memory"));
+        for node in memory_nodes {
+            for region in node.reg().into_iter().flatten() {
+                let start = region.starting_address as usize;
+                map.ram.insert(start..start + region.size.unwrap_or(0));
+            }
+        }
+
+        for reservation in fdt.memory_reservations() {
+            let start = reservation.address() as usize;
+            map.reserve(start..start + reservation.size(), Reservation::MemReserve);
+        }
+
+        // Nodes without a reg are dynamically placed, and only describe what
+        // the OS should allocate itself
+        if let Some(reserved_memory) = fdt.find_node("/reserved-memory") {
+            for node in reserved_memory.children() {
+                for region in node.reg().into_iter().flatten() {
+                    let start = region.starting_address as usize;
+                    map.reserve(
+                        start..start + region.size.unwrap_or(0),
+                        Reservation::ReservedMemory(node.name),
+                    );
+                }
+            }
+        }
+
+        map.reserve(memory::get_kernel_phys_range(), Reservation::Kernel);
+        map.reserve(fdt_addr..fdt_addr + fdt.total_size(), Reservation::Fdt);
+        if let Some(initrd) = initrd {
+            map.reserve(initrd, Reservation::Initrd);
+        }
+
+        // Only whole pages of what is left over can be used
+        let page_size = PageSize::Normal.size();
+        for range in map.ram.iter() {
+            let start = memory::align_up(range.start, page_size);
+            let end = range.end & !(page_size - 1);
+            if start < end {
+                map.usable.insert(start..end);
+            }
+        }
+        for (range, _) in map.reserved.iter().flatten() {
+            let start = range.start & !(page_size - 1);
+            let end = memory::align_up(range.end, page_size);
+            map.usable.remove(start..end);
+        }
+
+        map
+    }
+
+    fn reserve(&mut self, range: Range<usize>, reason: Reservation) {
+        if range.is_empty() {
+            return;
+        }
+        let slot = self
+            .reserved
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .expect("too many reserved memory ranges");
+        *slot = Some((range, reason));
+    }
+
+    /// Every range of RAM, including reserved ranges.
+    pub fn ram(&self) -> &RangeSet {
+        &self.ram
+    }
+
+    /// The ranges of RAM that must not be used, and why.
+    pub fn reserved(&self) -> impl Iterator<Item = &(Range<usize>, Reservation)> {
+        self.reserved.iter().flatten()
+    }
+
+    /// The page-aligned ranges of RAM that are free to use.
+    pub fn usable(&self) -> &RangeSet {
+        &self.usable
+    }
+
+    /// The first address past the end of RAM.
+    pub fn end(&self) -> usize {
+        self.ram.iter().map(|range| range.end).max().unwrap_or(0)
+    }
+
+    /// Log the memory map.
+    pub fn print(&self) {
+        info!("physical memory map:");
+        for range in self.ram.iter() {
+            info!("  {:#012X}-{:#012X} ram", range.start, range.end);
+        }
+        for (range, reason) in self.reserved() {
+            info!(
+                "  {:#012X}-{:#012X} reserved ({})",
+                range.start, range.end, reason
+            );
+        }
+        for range in self.usable.iter() {
+            info!("  {:#012X}-{:#012X} usable", range.start, range.end);
+        }
+        info!(
+            "{} KiB of {} KiB ram usable",
+            self.usable.size() / 1024,
+            self.ram.size() / 1024
+        );
+    }
+}
+
+/// Build the memory map from the FDT and print it.
+pub fn init(
+    fdt: &Fdt<'static>,
+    fdt_addr: usize,
+    initrd: Option<Range<usize>>,
+) -> &'static MemoryMap {
+    let map = MEMORY_MAP.get_or_init(|| MemoryMap::from_fdt(fdt, fdt_addr, initrd));
+    map.print();
+    map
+}
+
+/// Get the memory map, once it has been built.
+#[allow(dead_code)]
+pub fn get() -> &'static MemoryMap {
+    MEMORY_MAP.get().expect("memory map not initialised")
+}
@@ -1,114 +1,362 @@
-use core::arch::asm;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use fdt::standard_nodes::MemoryRegion;
-use log::info;
+use conquer_once::spin::OnceCell;
+use log::{debug, info};
 
-use crate::{allocator, csr, paging};
+use crate::{
+    allocator,
+    cmdline::{parse_size, Param, ParamSpec},
+    csr,
+    frame::{self, FrameAllocator},
+    memmap::MemoryMap,
+    paging,
+    riscv::tlb,
+};
 
-pub const HEAP_START: *mut u8 = 0xFFFF_FFC0_0000_0000 as *mut _;
-pub const HEAP_SIZE: usize = 48 * 1024 * 1024; // 16 MiB
+/// Virtual address the kernel is linked at, must match virt.lds.
+pub const KERNEL_VIRT_BASE: usize = 0xFFFF_FFFF_8000_0000;
+
+/// Virtual address that all of physical memory is mapped at, must match boot.S.
+pub const PHYSMAP_START: usize = 0xFFFF_FFD0_0000_0000;
+/// Size of the physmap, which limits how much physical memory can be used.
+pub const PHYSMAP_SIZE: usize = 0x20_0000_0000; // 128 GiB
+
+/// Virtual address the kernel heap starts at.
+pub const HEAP_START: usize = 0xFFFF_FFC0_0000_0000;
+/// Size of the virtual address range reserved for the heap, up to the physmap.
+const HEAP_RESERVED: usize = PHYSMAP_START - HEAP_START;
+/// Memory mapped for the heap at boot, before it needs to grow.
+const HEAP_INITIAL_SIZE: usize = 1024 * 1024; // 1 MiB
+/// The heap grows to a multiple of this, so that it can use megapages.
+const HEAP_GROW_ALIGN: usize = paging::PageSize::Mega.size();
+
+/// Virtual address of the region for vmalloc areas, between the physmap and
+/// the kernel.
+pub const VMALLOC_START: usize = PHYSMAP_START + PHYSMAP_SIZE;
+/// Size of the vmalloc region.
+pub const VMALLOC_SIZE: usize = 0x8_0000_0000; // 32 GiB
+
+/// Address space identifier used by the kernel's page table.
+pub const KERNEL_ASID: usize = 0;
+
+/// Maximum size of the kernel heap, e.g. `heap=64M`.
+static HEAP_SIZE: Param<usize> = Param::new("heap", parse_heap_size);
+/// Smallest heap size accepted on the command line, which leaves the allocator
+/// room for its first free block.
+const HEAP_MIN_SIZE: usize = paging::PageSize::Normal.size();
+
+pub static PARAMS: &[&dyn ParamSpec] = &[&HEAP_SIZE];
+
+fn parse_heap_size(value: &str) -> Option<usize> {
+    parse_size(value).filter(|&size| size >= HEAP_MIN_SIZE)
+}
+
+/// The value of satp that points at the kernel's page table.
+static KERNEL_SATP: OnceCell<csr::Satp> = OnceCell::uninit();
+/// The kernel's root page table, whose upper half is shared by every address
+/// space.
+static KERNEL_TABLE: OnceCell<spin::Mutex<&'static mut paging::PageTable>> = OnceCell::uninit();
+
+/// End of the memory that is mapped for the heap.
+static HEAP_END: AtomicUsize = AtomicUsize::new(0);
+/// The heap can't grow past this address.
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(0);
 
 // These symbols are exposed by the linkerscript
 extern "C" {
     static __kernel_start: u8;
     static __kernel_end: u8;
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
 }
 
-pub struct FrameAllocator<I: Iterator<Item = usize>> {
-    available_pages: I,
+// This symbol is exposed by boot.S
+extern "C" {
+    static kernel_phys_base: usize;
 }
 
-impl FrameAllocator<core::iter::StepBy<core::ops::Range<usize>>> {
-    pub fn new(base: usize, size: usize) -> Self {
-        let end = base + size;
-        let i = (base..end).step_by(paging::PageSize::Normal.size());
-        Self { available_pages: i }
+/// Initialise the kernel's page table and heap.
+///
+/// Frames are only handed out from the usable ranges of the memory map. The
+/// largest paging mode up to `max_mode` that the hart supports is used.
+pub fn init(memory_map: &MemoryMap, max_mode: paging::PagingMode) {
+    // Find out where the kernel file was loaded to
+    let (kernel_virt_start, _) = get_kernel_range();
+    let kernel = get_kernel_phys_range();
+    info!(
+        "kernel loaded from {:X} to {:X}, mapped at {:X}",
+        kernel.start, kernel.end, kernel_virt_start
+    );
+    if !memory_map.ram().contains(&kernel) {
+        panic!("kernel not loaded in memory segment");
     }
-}
+    if memory_map.end() > PHYSMAP_SIZE {
+        panic!("memory past {:X} doesn't fit in the physmap", PHYSMAP_SIZE);
+    }
+
+    // Create an allocator with every usable page
+    frame::init(memory_map);
+    let mut frame_allocator = frame::lock();
+
+    // Use the largest paging mode the harts support. Writing satp with an
+    // unsupported mode has no effect, so each mode can be tried in turn.
+    let mut mode = max_mode;
+    let (table, satp) = loop {
+        paging::set_mode(mode);
+        let table = build_kernel_table(memory_map, &mut frame_allocator);
+
+        let mut satp = csr::Satp::read();
+        satp.set_asid(KERNEL_ASID as u64);
+        satp.set_mode(mode.satp_mode());
+        satp.set_ppn(paging::PageTable::ppn(table) as u64);
+        satp.write();
+        if csr::Satp::read().mode() == mode.satp_mode() {
+            break (table, satp);
+        }
+
+        paging::PageTable::free(table, &mut frame_allocator);
+        mode = mode.smaller().expect("hart doesn't support Sv39");
+    };
+    // The boot page table used the same ASID, so every entry cached from it has
+    // to be flushed
+    tlb::flush_all();
+    KERNEL_SATP.init_once(|| satp);
+    info!("using {:?} paging", mode);
 
-impl<I: Iterator<Item = usize>> FrameAllocator<I> {
-    pub fn next(&mut self) -> Option<*mut u8> {
-        self.available_pages.next().map(|addr| addr as _)
+    // Only map the start of the heap, it grows when the allocator runs out
+    let page_size = paging::PageSize::Normal.size();
+    let heap_limit =
+        align_up(HEAP_SIZE.get().unwrap_or(HEAP_RESERVED), page_size).min(HEAP_RESERVED);
+    let heap_start = HEAP_START;
+    let heap_size = HEAP_INITIAL_SIZE.min(heap_limit);
+    if map_heap(table, heap_start, heap_size, &mut frame_allocator) < heap_size {
+        panic!("out of memory for the heap");
     }
+    HEAP_END.store(heap_start + heap_size, Ordering::Relaxed);
+    HEAP_LIMIT.store(heap_start + heap_limit, Ordering::Relaxed);
+    info!(
+        "heap at 0x{:X}, {} KiB mapped of up to {} MiB",
+        heap_start,
+        heap_size / 1024,
+        heap_limit / (1024 * 1024)
+    );
+
+    // Other address spaces copy the kernel's root entries when they are created,
+    // so these must not change afterwards
+    table
+        .preallocate_kernel_half(&mut frame_allocator)
+        .expect("out of memory for the kernel's page tables");
+
+    let stats = frame_allocator.stats();
+    info!(
+        "{} of {} frames used after mapping the kernel and heap",
+        stats.used(),
+        stats.total
+    );
+    drop(frame_allocator);
+    KERNEL_TABLE.init_once(|| spin::Mutex::new(table));
+
+    // Mapping the heap created new page tables, which flushing single
+    // addresses doesn't cover, and its pages are global, which flushing the
+    // kernel's ASID doesn't cover either
+    tlb::flush_all();
+
+    // Initialise the memory allocator
+    allocator::init(|| allocator::HeapAllocator::new(HEAP_START as *mut u8, heap_size));
 }
 
-pub fn init(mut regions: impl Iterator<Item = MemoryRegion>) {
-    // Get the main memory region
-    let region = regions.next().expect("no memory regions found");
-    let remaining_regions = regions.count();
-    if remaining_regions > 0 {
-        panic!("multiple memory regions found");
+/// Map frames for the heap from `start`, returning how much could be mapped
+/// before running out of memory.
+fn map_heap(
+    table: &mut paging::PageTable,
+    start: usize,
+    length: usize,
+    frame_allocator: &mut FrameAllocator,
+) -> usize {
+    let mut mapped = 0;
+    while mapped < length {
+        let virt_addr = start + mapped;
+        let remaining = length - mapped;
+        // Use the largest pages the length and alignment allow, falling back to
+        // smaller pages for the remainder or if memory is too fragmented
+        let frame = [
+            paging::PageSize::Mega,
+            paging::PageSize::Napot64K,
+            paging::PageSize::Normal,
+        ]
+        .into_iter()
+        .filter(|size| *size != paging::PageSize::Napot64K || paging::svnapot())
+        .filter(|size| remaining >= size.size() && virt_addr & (size.size() - 1) == 0)
+        .find_map(|size| {
+            frame_allocator
+                .alloc(size.order())
+                .map(|frame| (frame, size))
+        });
+        let Some((frame, page_size)) = frame else {
+            break;
+        };
+
+        // Map the frame to the heap
+        let result = table.map(
+            paging::VirtualAddress(virt_addr as u64),
+            paging::PhysicalAddress(frame as u64),
+            page_size,
+            paging::MapFlags::KERNEL_RW,
+            frame_allocator,
+        );
+        if result.is_err() {
+            // There was no memory left for a page table
+            frame_allocator.free(frame, page_size.order());
+            break;
+        }
+        mapped += page_size.size();
     }
+    mapped
+}
 
-    // Find out where the kernel file was loaded to
-    let (kernel_start, kernel_end) = get_kernel_range();
-    info!("kernel loaded from {:X} to {:X}", kernel_start, kernel_end);
-    let kernel_size = kernel_end - kernel_start;
-
-    // Ensure this is the main memory segment
-    if kernel_end < region.starting_address as usize
-        || kernel_end
-            > (region.starting_address as usize
-                + region.size.expect("memory region has to have a size"))
-    {
-        panic!("kernel not loaded in memory segment");
+/// Map more memory at the end of the heap, so that at least `min` more bytes
+/// fit in it.
+///
+/// Returns the number of bytes added, which is less than `min` if the heap
+/// reached its limit or memory ran out. This must only be called by the
+/// allocator, with its lock held.
+pub fn grow_heap(min: usize) -> usize {
+    let end = HEAP_END.load(Ordering::Relaxed);
+    let limit = HEAP_LIMIT.load(Ordering::Relaxed);
+    let new_end = align_up(end.saturating_add(min), HEAP_GROW_ALIGN).min(limit);
+    if new_end <= end {
+        return 0;
     }
 
-    // Calculate the remaining space
-    let memory_base = align_up(kernel_end, paging::PageSize::Normal.size());
-    let memory_size = region.size.unwrap() - kernel_size;
-    info!(
-        "using memory segment at address {:X} with size {:X} bytes",
-        memory_base, memory_size
-    );
+    let mut table = kernel_table();
+    let mapped = map_heap(&mut table, end, new_end - end, &mut frame::lock());
+    drop(table);
+    flush_new_kernel_mappings();
 
-    // Create an allocator with this space
-    let mut frame_allocator = FrameAllocator::new(memory_base, memory_size);
+    HEAP_END.store(end + mapped, Ordering::Relaxed);
+    debug!("heap grown to 0x{:X}", end + mapped);
+    mapped
+}
 
-    // Create a new page table
-    let table = frame_allocator.next().unwrap();
+/// Build the kernel's page table for the current paging mode.
+fn build_kernel_table(
+    memory_map: &MemoryMap,
+    frame_allocator: &mut FrameAllocator,
+) -> &'static mut paging::PageTable {
+    let table = frame_allocator.alloc(0).unwrap();
     let table = unsafe { paging::PageTable::new(table) };
-    table.setup_identity_map();
-
-    // Update satp with the new page table
-    let mut satp = csr::Satp::read();
-    satp.set_asid(0);
-    satp.set_mode(8);
-    satp.set_ppn(paging::PageTable::ppn(table) as u64);
-    satp.write();
-
-    // Allocate memory for entire heap range
-    let mut length = HEAP_SIZE;
-    let mut virt_addr = HEAP_START;
-    while length > 0 {
-        // Find a free frame
-        let next_page = frame_allocator.next().unwrap();
+    // All of RAM is reached through the physmap, as are devices, so anything at
+    // the start of the address space that isn't RAM is mapped as a device. This
+    // leaves the lower half of the address space empty.
+    let page_size = paging::PageSize::Normal.size();
+    let physmap_end = align_up(memory_map.end().max(0x1_0000_0000), page_size);
+    let mut device_start = 0;
+    for ram in memory_map.ram().iter() {
+        let ram = (ram.start & !(page_size - 1))..align_up(ram.end, page_size);
+        map_physmap(
+            table,
+            device_start..ram.start,
+            paging::MapFlags::KERNEL_MMIO,
+            frame_allocator,
+        );
+        map_physmap(
+            table,
+            ram.clone(),
+            paging::MapFlags::KERNEL_RW,
+            frame_allocator,
+        );
+        device_start = ram.end;
+    }
+    map_physmap(
+        table,
+        device_start..physmap_end,
+        paging::MapFlags::KERNEL_MMIO,
+        frame_allocator,
+    );
 
-        // Map the frame to the heap
+    // Map the kernel image into the higher half, with nothing both writable
+    // and executable
+    let sections = unsafe {
+        [
+            (&__text_start, &__text_end, paging::MapFlags::KERNEL_RX),
+            (&__rodata_start, &__rodata_end, paging::MapFlags::KERNEL_R),
+            (&__data_start, &__data_end, paging::MapFlags::KERNEL_RW),
+        ]
+    };
+    for (start, end, flags) in sections {
+        let start = start as *const u8 as usize;
+        let end = end as *const u8 as usize;
         table
-            .map(
-                paging::Sv39Virtual(virt_addr as u64),
-                paging::Sv39Physical(next_page as u64),
-                &mut frame_allocator,
+            .map_range(
+                paging::VirtualAddress(start as u64),
+                paging::PhysicalAddress(kernel_virt_to_phys(start) as u64),
+                end - start,
+                flags,
+                frame_allocator,
             )
             .unwrap();
-
-        virt_addr = unsafe { virt_addr.add(paging::PageSize::Normal.size()) };
-        length -= paging::PageSize::Normal.size();
     }
 
-    let heap_start = HEAP_START as usize;
-    let heap_end = heap_start + HEAP_SIZE - 4096;
-    info!("heap mapped from 0x{:X} to 0x{:X}", heap_start, heap_end);
+    table
+}
 
-    // Flush TLB
-    // TODO: Only flush this ASID and relevant address range
-    unsafe {
-        asm!("sfence.vma x0, x0");
+/// Map a range of physical addresses into the physmap.
+fn map_physmap(
+    table: &mut paging::PageTable,
+    range: Range<usize>,
+    flags: paging::MapFlags,
+    frame_allocator: &mut FrameAllocator,
+) {
+    if range.is_empty() {
+        return;
     }
+    table
+        .map_range(
+            paging::VirtualAddress(phys_to_virt(range.start) as u64),
+            paging::PhysicalAddress(range.start as u64),
+            range.len(),
+            flags,
+            frame_allocator,
+        )
+        .unwrap();
+}
 
-    // Initialise the memory allocator
-    allocator::init(|| allocator::FixedSizeBlockAllocator::new(HEAP_START, HEAP_SIZE));
+/// Switch the current hart onto the kernel's page table.
+///
+/// The boot hart must have already called [`init`].
+pub fn init_hart() {
+    KERNEL_SATP.get().unwrap().write();
+    tlb::flush_all();
+}
+
+/// Get the value of satp that points at the kernel's page table.
+pub fn kernel_satp() -> csr::Satp {
+    *KERNEL_SATP
+        .get()
+        .expect("kernel page table not initialised")
+}
+
+/// Make pages just mapped in the kernel half visible to every hart.
+///
+/// New page tables may have been created, which flushing single addresses
+/// doesn't cover. Other harts can't be left to fault on the pages and flush
+/// then, as a fault taken while handling a trap would overwrite the trap's
+/// saved registers.
+pub fn flush_new_kernel_mappings() {
+    tlb::shootdown_all();
+}
+
+/// Lock the kernel's page table.
+pub fn kernel_table() -> spin::MutexGuard<'static, &'static mut paging::PageTable> {
+    KERNEL_TABLE
+        .get()
+        .expect("kernel page table not initialised")
+        .lock()
 }
 
 fn get_kernel_range() -> (usize, usize) {
@@ -120,9 +368,43 @@ fn get_kernel_range() -> (usize, usize) {
     }
 }
 
+/// Get the physical address range the kernel image was loaded at.
+pub fn get_kernel_phys_range() -> Range<usize> {
+    let (start, end) = get_kernel_range();
+    kernel_virt_to_phys(start)..kernel_virt_to_phys(end)
+}
+
+/// Translate the virtual address of something in the kernel image to the
+/// physical address it was loaded at.
+pub fn kernel_virt_to_phys(addr: usize) -> usize {
+    addr - KERNEL_VIRT_BASE + get_kernel_phys_base()
+}
+
+/// Get the address in the physmap of a physical address.
+pub fn phys_to_virt(phys: usize) -> usize {
+    assert!(phys < PHYSMAP_SIZE, "{:X} is outside the physmap", phys);
+    PHYSMAP_START + phys
+}
+
+/// Get the physical address of an address in the physmap or the kernel image.
+pub fn virt_to_phys(virt: usize) -> usize {
+    if (PHYSMAP_START..PHYSMAP_START + PHYSMAP_SIZE).contains(&virt) {
+        virt - PHYSMAP_START
+    } else if virt >= KERNEL_VIRT_BASE {
+        kernel_virt_to_phys(virt)
+    } else {
+        panic!("{:X} is not in the physmap or the kernel image", virt);
+    }
+}
+
+/// Get the physical address the kernel was loaded at, as found by boot.S.
+fn get_kernel_phys_base() -> usize {
+    unsafe { kernel_phys_base }
+}
+
 /// Align the given address `addr` upwards to alignment `align`.
 ///
 /// Requires that `align` is a power of two.
-pub fn align_up(addr: usize, align: usize) -> usize {
+pub const fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
@@ -0,0 +1,158 @@
+use core::cell::UnsafeCell;
+use core::mem;
+use core::ptr::NonNull;
+
+use crate::vmalloc;
+
+/// A register that can only be read.
+#[repr(transparent)]
+pub struct ReadOnly<T>(UnsafeCell<T>);
+
+/// A register that can only be written.
+#[repr(transparent)]
+pub struct WriteOnly<T>(UnsafeCell<T>);
+
+/// A register that can be read and written.
+#[repr(transparent)]
+pub struct ReadWrite<T>(UnsafeCell<T>);
+
+/// Padding of `N` bytes between registers, which is never accessed.
+#[repr(transparent)]
+pub struct Reserved<const N: usize>([u8; N]);
+
+// # Safety
+// Registers are only accessed with single volatile reads and writes.
+unsafe impl<T: Send> Sync for ReadOnly<T> {}
+unsafe impl<T: Send> Sync for WriteOnly<T> {}
+unsafe impl<T: Send> Sync for ReadWrite<T> {}
+
+impl<T: Copy> ReadOnly<T> {
+    pub fn read(&self) -> T {
+        // # Safety
+        // The register is in mapped device memory.
+        unsafe { self.0.get().read_volatile() }
+    }
+}
+
+#[allow(dead_code)]
+impl<T: Copy> WriteOnly<T> {
+    pub fn write(&self, value: T) {
+        // # Safety
+        // The register is in mapped device memory.
+        unsafe { self.0.get().write_volatile(value) }
+    }
+}
+
+impl<T: Copy> ReadWrite<T> {
+    pub fn read(&self) -> T {
+        // # Safety
+        // The register is in mapped device memory.
+        unsafe { self.0.get().read_volatile() }
+    }
+
+    pub fn write(&self, value: T) {
+        // # Safety
+        // The register is in mapped device memory.
+        unsafe { self.0.get().write_volatile(value) }
+    }
+
+    /// Read the register, then write back the value `f` makes from it.
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+}
+
+/// A type that can be laid over device memory, as it is made only of
+/// registers, padding and arrays of them, e.g.
+///
+/// ```ignore
+/// #[repr(C)]
+/// struct Registers {
+///     status: ReadOnly<u32>,
+///     _reserved: Reserved<4>,
+///     control: ReadWrite<u32>,
+/// }
+/// unsafe impl RegisterBlock for Registers {}
+/// ```
+///
+/// # Safety
+/// The type must be `repr(C)` or `repr(transparent)`, and only hold register
+/// types, `Reserved` padding and other register blocks.
+pub unsafe trait RegisterBlock {}
+
+unsafe impl<T> RegisterBlock for ReadOnly<T> {}
+unsafe impl<T> RegisterBlock for WriteOnly<T> {}
+unsafe impl<T> RegisterBlock for ReadWrite<T> {}
+unsafe impl<const N: usize> RegisterBlock for Reserved<N> {}
+unsafe impl<T: RegisterBlock, const N: usize> RegisterBlock for [T; N] {}
+
+/// A device's memory, mapped with [`vmalloc::ioremap`] and unmapped when
+/// dropped.
+pub struct IoMapping {
+    base: NonNull<u8>,
+    size: usize,
+}
+
+// # Safety
+// The mapping is only accessed through registers.
+unsafe impl Send for IoMapping {}
+unsafe impl Sync for IoMapping {}
+
+impl IoMapping {
+    /// Map `size` bytes of device memory at the physical address `phys`.
+    ///
+    /// Returns None if there is no room to map it.
+    pub fn new(phys: usize, size: usize) -> Option<Self> {
+        Some(Self {
+            base: vmalloc::ioremap(phys, size)?,
+            size,
+        })
+    }
+
+    /// Virtual address the device's memory starts at.
+    pub fn base(&self) -> usize {
+        self.base.as_ptr() as usize
+    }
+
+    /// Get the register block at an offset into the device's memory.
+    ///
+    /// Panics if the block isn't aligned or doesn't fit in the mapping.
+    pub fn block<T: RegisterBlock>(&self, offset: usize) -> &T {
+        &self.blocks(offset, 1)[0]
+    }
+
+    /// Get `count` consecutive register blocks at an offset into the device's
+    /// memory.
+    ///
+    /// Panics if the blocks aren't aligned or don't fit in the mapping.
+    pub fn blocks<T: RegisterBlock>(&self, offset: usize, count: usize) -> &[T] {
+        let end = mem::size_of::<T>()
+            .checked_mul(count)
+            .and_then(|len| len.checked_add(offset));
+        assert!(
+            end.is_some_and(|end| end <= self.size),
+            "registers at {:#X} outside of the {:#X} byte mapping",
+            offset,
+            self.size
+        );
+        let addr = self.base() + offset;
+        assert_eq!(
+            addr & (mem::align_of::<T>() - 1),
+            0,
+            "misaligned registers at {:#X}",
+            offset
+        );
+        // # Safety
+        // The blocks are inside the mapping, which lives as long as the
+        // reference, and are only made of registers.
+        unsafe { core::slice::from_raw_parts(addr as *const T, count) }
+    }
+}
+
+impl Drop for IoMapping {
+    fn drop(&mut self) {
+        // # Safety
+        // The registers borrow the mapping, so none are left.
+        unsafe { vmalloc::iounmap(self.base) };
+    }
+}
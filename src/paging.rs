@@ -1,12 +1,151 @@
+use core::ops::{BitOr, BitOrAssign, Range};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
 use bitfield::bitfield;
-use log::warn;
+use fdt::Fdt;
+use log::{info, warn};
+
+use crate::{csr, frame::FrameAllocator, isa, memory, riscv::tlb};
+
+/// The paging mode in use, as its value in satp.
+static MODE: AtomicU8 = AtomicU8::new(PagingMode::Sv39 as u8);
+
+/// Whether the harts support the Svpbmt extension, so mappings can have a
+/// memory type.
+static SVPBMT: AtomicBool = AtomicBool::new(false);
+/// Whether the harts support the Svnapot extension, so groups of normal pages
+/// can share a TLB entry.
+static SVNAPOT: AtomicBool = AtomicBool::new(false);
+
+/// A virtual memory scheme, which differ in the number of levels of page
+/// tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum PagingMode {
+    Sv39 = 8,
+    Sv48 = 9,
+    Sv57 = 10,
+}
+
+impl PagingMode {
+    /// Number of levels of page tables.
+    pub const fn levels(&self) -> usize {
+        match self {
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+            PagingMode::Sv57 => 5,
+        }
+    }
+
+    /// Number of bits in a virtual address.
+    pub const fn virt_bits(&self) -> usize {
+        PageSize::Normal.bits() + 9 * self.levels()
+    }
 
-use crate::memory::FrameAllocator;
+    /// Value of the mode field of satp.
+    pub const fn satp_mode(&self) -> u64 {
+        *self as u64
+    }
+
+    /// The mode with one fewer level, if there is one.
+    pub const fn smaller(&self) -> Option<Self> {
+        match self {
+            PagingMode::Sv39 => None,
+            PagingMode::Sv48 => Some(PagingMode::Sv39),
+            PagingMode::Sv57 => Some(PagingMode::Sv48),
+        }
+    }
+
+    /// Parse the `mmu-type` property of a cpu node.
+    pub fn from_mmu_type(mmu_type: &str) -> Option<Self> {
+        match mmu_type {
+            "riscv,sv39" => Some(PagingMode::Sv39),
+            "riscv,sv48" => Some(PagingMode::Sv48),
+            "riscv,sv57" => Some(PagingMode::Sv57),
+            _ => None,
+        }
+    }
+
+    /// The largest mode that every hart in the FDT claims to support.
+    ///
+    /// Harts without an `mmu-type` are assumed to support every mode, so this
+    /// must still be checked on the hardware.
+    pub fn max_supported(fdt: &Fdt) -> Self {
+        fdt.cpus()
+            .filter_map(|cpu| cpu.property("mmu-type")?.as_str())
+            .filter_map(|mmu_type| {
+                let mode = PagingMode::from_mmu_type(mmu_type);
+                if mode.is_none() {
+                    warn!("unsupported mmu-type {:?}", mmu_type);
+                }
+                mode
+            })
+            .min()
+            .unwrap_or(PagingMode::Sv57)
+    }
+}
+
+/// Get the paging mode that page tables are built for.
+pub fn mode() -> PagingMode {
+    match MODE.load(Ordering::Relaxed) {
+        8 => PagingMode::Sv39,
+        9 => PagingMode::Sv48,
+        _ => PagingMode::Sv57,
+    }
+}
+
+/// Set the paging mode that page tables are built for.
+///
+/// Every existing page table must be for the same mode, so this can only be
+/// changed while the kernel builds its first page table.
+pub fn set_mode(mode: PagingMode) {
+    MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+/// Check which paging extensions every hart supports.
+///
+/// This must be called before any page tables are built.
+pub fn detect_extensions(fdt: &Fdt) {
+    let svpbmt = isa::all_harts_have_extension(fdt, "svpbmt");
+    SVPBMT.store(svpbmt, Ordering::Relaxed);
+    info!(
+        "Svpbmt {}",
+        if svpbmt { "supported" } else { "not supported" }
+    );
+
+    let svnapot = isa::all_harts_have_extension(fdt, "svnapot");
+    SVNAPOT.store(svnapot, Ordering::Relaxed);
+    info!(
+        "Svnapot {}",
+        if svnapot {
+            "supported"
+        } else {
+            "not supported"
+        }
+    );
+}
+
+/// Check whether mappings can have a memory type other than PMA.
+pub fn svpbmt() -> bool {
+    SVPBMT.load(Ordering::Relaxed)
+}
+
+/// Check whether 64 KiB NAPOT mappings can be used.
+pub fn svnapot() -> bool {
+    SVNAPOT.load(Ordering::Relaxed)
+}
+
+/// Number of levels of page tables in the current mode.
+fn levels() -> usize {
+    mode().levels()
+}
 
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum PageSize {
     Normal,
+    /// 16 contiguous normal pages, mapped as one with Svnapot.
+    Napot64K,
     Mega,
     Giga,
     Tera,
@@ -18,6 +157,7 @@ impl PageSize {
     pub const fn bits(&self) -> usize {
         match self {
             PageSize::Normal => 12,
+            PageSize::Napot64K => 16,
             PageSize::Mega => 21,
             PageSize::Giga => 30,
             PageSize::Tera => 39,
@@ -30,6 +170,63 @@ impl PageSize {
         1 << self.bits()
     }
 
+    /// Frame allocation order of the page.
+    pub const fn order(&self) -> usize {
+        self.bits() - PageSize::Normal.bits()
+    }
+
+    /// Number of page table entries used to map the page.
+    pub const fn entries(&self) -> usize {
+        match self {
+            PageSize::Napot64K => 16,
+            _ => 1,
+        }
+    }
+
+    /// Level of the page table that a leaf of this size is found at.
+    pub const fn level(&self) -> usize {
+        match self {
+            PageSize::Normal | PageSize::Napot64K => 0,
+            PageSize::Mega => 1,
+            PageSize::Giga => 2,
+            PageSize::Tera => 3,
+            PageSize::Peta => 4,
+        }
+    }
+
+    /// Size of a leaf at the given level of the page table.
+    pub const fn from_level(level: usize) -> Self {
+        match level {
+            0 => PageSize::Normal,
+            1 => PageSize::Mega,
+            2 => PageSize::Giga,
+            3 => PageSize::Tera,
+            _ => PageSize::Peta,
+        }
+    }
+
+    /// Largest page size supported by the paging mode that both addresses are
+    /// aligned to and that fits in `length`. Below a megapage, 64 KiB NAPOT
+    /// pages are used when Svnapot is available.
+    pub fn largest_aligned(virt: usize, phys: usize, length: usize) -> Self {
+        let mut level = levels() - 1;
+        loop {
+            let size = PageSize::from_level(level).size();
+            if level == 0 || ((virt | phys) & (size - 1) == 0 && length >= size) {
+                break;
+            }
+            level -= 1;
+        }
+
+        let napot_size = PageSize::Napot64K.size();
+        if level == 0 && svnapot() && (virt | phys) & (napot_size - 1) == 0 && length >= napot_size
+        {
+            PageSize::Napot64K
+        } else {
+            PageSize::from_level(level)
+        }
+    }
+
     #[allow(dead_code)]
     pub const fn round_down(size: usize) -> Self {
         if size >= PageSize::Peta.size() {
@@ -51,127 +248,645 @@ pub struct PageTable {
 }
 
 impl PageTable {
+    /// Create an empty table in the frame at the physical address `phys`.
+    ///
     /// # Safety
-    /// The supplied address must be page-aligned and valid for an entire page.
-    pub unsafe fn new(addr: *mut u8) -> &'static mut PageTable {
-        assert_eq!(addr as usize % PageSize::Normal.size(), 0);
+    /// The supplied address must be page-aligned, and the frame must not be
+    /// used for anything else.
+    pub unsafe fn new(phys: usize) -> &'static mut PageTable {
+        assert_eq!(phys & (PageSize::Normal.size() - 1), 0);
 
-        let addr = addr as *mut PageTable;
+        let addr = memory::phys_to_virt(phys) as *mut PageTable;
         unsafe {
             addr.write_bytes(0, 1);
             &mut *addr
         }
     }
 
+    /// Physical address of the frame holding the table.
+    pub fn phys(table: *const PageTable) -> usize {
+        memory::virt_to_phys(table as usize)
+    }
+
     pub fn ppn(table: *const PageTable) -> usize {
-        (table as usize) >> PageSize::Normal.bits()
+        PageTable::phys(table) >> PageSize::Normal.bits()
     }
 
-    /// Setup an identity mapped region at the start of virtual memory
+    /// Map a page of memory of the given size.
     ///
-    /// This should only be called on the root table
-    pub fn setup_identity_map(&mut self) {
-        let max_map_addr: usize = 0x1_0000_0000;
-        let page_size = PageSize::Giga;
-
-        let mut current_base = 0;
-        for entry in self.inner.iter_mut() {
-            //info!("identity mapping 0x{:X}", current_base);
-
-            let phys_addr = Sv39Physical(current_base as u64);
-
-            entry.set_r(true);
-            entry.set_w(true);
-            entry.set_x(true);
-            entry.set_ppn_2(phys_addr.ppn_2());
-            entry.set_ppn_1(phys_addr.ppn_1());
-            entry.set_ppn_0(phys_addr.ppn_0());
-            entry.set_valid(true);
+    /// Both addresses must be aligned to the page size. A
+    /// [`Napot64K`](PageSize::Napot64K) page fills 16 consecutive entries and
+    /// needs Svnapot. This should only be called on the root table.
+    ///
+    /// Returns Err if the requested region was already mapped.
+    pub fn map(
+        &mut self,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        page_size: PageSize,
+        flags: MapFlags,
+        frame_allocator: &mut FrameAllocator,
+    ) -> Result<(), ()> {
+        assert!(flags.is_valid_leaf(), "invalid leaf flags {:?}", flags);
+        let level = page_size.level();
+        assert!(level < levels(), "page size not supported by {:?}", mode());
+        assert!(
+            page_size != PageSize::Napot64K || svnapot(),
+            "Svnapot not supported"
+        );
+        assert_eq!(virt.0 as usize % page_size.size(), 0);
+        assert_eq!(phys.0 as usize % page_size.size(), 0);
+
+        // Descend page tables down to the leaf's level, creating any missing
+        // tables
+        let mut table: &mut PageTable = self;
+        for level in (level + 1..levels()).rev() {
+            let entry = &mut table.inner[virt.vpn(level) as usize];
+            table = descend_table(entry, frame_allocator)?;
+        }
+        let index = virt.vpn(level) as usize;
+        let entries = &mut table.inner[index..index + page_size.entries()];
+        if entries.iter().any(|entry| entry.valid()) {
+            warn!("entry points to a an existing mapping or table");
+            return Err(());
+        }
 
-            current_base += page_size.size();
-            if current_base >= max_map_addr {
-                break;
+        for entry in entries {
+            // Setup the entry to map to the desired physical address. NAPOT
+            // entries encode their size in the low bits of the PPN.
+            if page_size == PageSize::Napot64K {
+                entry.set_ppn(phys.ppn() | NAPOT_64K_PPN);
+                entry.set_n(true);
+            } else {
+                entry.set_ppn(phys.ppn());
             }
+
+            entry.set_flags(flags);
+            entry.set_valid(true);
         }
+
+        Ok(())
     }
 
-    /// Map a page of memory.
+    /// Map a physically contiguous region of memory, using the largest pages
+    /// that the alignment of the addresses allows.
     ///
+    /// Both addresses and the length must be aligned to the normal page size.
     /// This should only be called on the root table.
+    pub fn map_range(
+        &mut self,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        length: usize,
+        flags: MapFlags,
+        frame_allocator: &mut FrameAllocator,
+    ) -> Result<(), ()> {
+        let (mut virt, mut phys) = (virt.0 as usize, phys.0 as usize);
+        let end = virt + length;
+        while virt < end {
+            let page_size = PageSize::largest_aligned(virt, phys, end - virt);
+            self.map(
+                VirtualAddress(virt as u64),
+                PhysicalAddress(phys as u64),
+                page_size,
+                flags,
+                frame_allocator,
+            )?;
+            virt += page_size.size();
+            phys += page_size.size();
+        }
+        Ok(())
+    }
+
+    /// Translate a virtual address to the physical address it is mapped to.
+    pub fn lookup(&mut self, virt: VirtualAddress) -> Result<PhysicalAddress, ()> {
+        let mut table: &mut PageTable = self;
+        for level in (0..levels()).rev() {
+            let entry = &mut table.inner[virt.vpn(level) as usize];
+            if !entry.valid() {
+                return Err(());
+            }
+            if !entry.next_level() {
+                // A leaf, which maps a huge page above level 0 or a NAPOT page
+                let page_size = entry.page_size(level);
+                let page = entry.as_physical_addr().unwrap().0;
+                let offset = virt.0 & (page_size.size() as u64 - 1);
+                return Ok(PhysicalAddress(page + offset));
+            }
+            table = entry.as_table_mut().unwrap();
+        }
+
+        // A table at level 0 is invalid
+        Err(())
+    }
+
+    /// Get the leaf entry that maps a virtual address, and the size of the page
+    /// it maps.
+    pub fn leaf_mut(&mut self, virt: VirtualAddress) -> Option<(&mut PageTableEntry, PageSize)> {
+        let mut table: &mut PageTable = self;
+        for level in (0..levels()).rev() {
+            let entry = &mut table.inner[virt.vpn(level) as usize];
+            if !entry.valid() {
+                return None;
+            }
+            if !entry.next_level() {
+                let page_size = entry.page_size(level);
+                return Some((entry, page_size));
+            }
+            table = entry.as_table_mut().unwrap();
+        }
+        None
+    }
+
+    /// Call `f` with the level and entry of every entry the hart would read to
+    /// translate a virtual address, stopping at a leaf or an invalid entry.
+    pub fn walk(&self, virt: VirtualAddress, mut f: impl FnMut(usize, &PageTableEntry)) {
+        let mut table = self;
+        for level in (0..levels()).rev() {
+            let entry = &table.inner[virt.vpn(level) as usize];
+            f(level, entry);
+            if !entry.valid() || !entry.next_level() {
+                return;
+            }
+            table = entry.as_table().unwrap();
+        }
+    }
+
+    /// Unmap every page in a range of virtual addresses in the address space
+    /// `asid`.
     ///
-    /// Returns Err if the requested region was already mapped.
-    pub fn map<I: Iterator<Item = usize>>(
+    /// Huge pages must lie entirely inside the range. If `free_frames` is set,
+    /// the frames the pages pointed to are returned to the frame allocator.
+    /// Page tables that are left empty are always freed. This should only be
+    /// called on the root table.
+    ///
+    /// Only this hart's TLB is flushed, so the caller must shoot down the range
+    /// on any other hart that may be using it.
+    ///
+    /// Returns Err if the range only covers part of a huge page, in which case
+    /// the pages before it have already been unmapped.
+    #[allow(dead_code)]
+    pub fn unmap(
         &mut self,
-        virt: Sv39Virtual,
-        phys: Sv39Physical,
-        frame_allocator: &mut FrameAllocator<I>,
+        range: Range<usize>,
+        asid: usize,
+        free_frames: bool,
+        frame_allocator: &mut FrameAllocator,
     ) -> Result<(), ()> {
-        // Extract the indexes into each of the tables
-        let vpn_0 = virt.vpn(0) as usize;
-        let vpn_1 = virt.vpn(1) as usize;
-        let vpn_2 = virt.vpn(2) as usize;
-
-        // Descend page tables, creating any missing tables
-        let entry = &mut self.inner[vpn_2];
-        let next = descend_table(entry, frame_allocator)?;
-        let entry = &mut next.inner[vpn_1];
-        let next = descend_table(entry, frame_allocator)?;
-        let entry = &mut next.inner[vpn_0];
-
-        // Setup the entry to map to the desired physical address
-        entry.set_ppn_0(phys.ppn_0());
-        entry.set_ppn_1(phys.ppn_1());
-        entry.set_ppn_2(phys.ppn_2());
-
-        // Set the entry's permissions
-        entry.set_r(true);
-        entry.set_w(true);
-        entry.set_x(true);
-
-        // TODO: Some implementations might need this depending on which A/D
-        //       mode they implement
-        //entry.set_accessed(true);
-        //entry.set_dirty(true);
+        let mut tables_freed = false;
+        let result = unmap_level(
+            self,
+            levels() - 1,
+            0,
+            &range,
+            asid,
+            free_frames,
+            &mut tables_freed,
+            frame_allocator,
+        );
+
+        // Flushing single addresses only covers leaf entries, and the kernel's
+        // entries are global, which flushing an ASID doesn't cover
+        if tables_freed {
+            if asid == memory::KERNEL_ASID {
+                tlb::flush_all();
+            } else {
+                tlb::flush_asid(asid);
+            }
+        }
+        result
+    }
 
-        entry.set_valid(true);
+    /// Change the flags of every page in a range of virtual addresses in
+    /// the address space `asid`.
+    ///
+    /// Huge pages must lie entirely inside the range. Pages keep their memory
+    /// type unless `flags` sets one other than PMA. This should only be called
+    /// on the root table.
+    ///
+    /// Returns Err if the flags aren't valid for a leaf, or if the range only
+    /// covers part of a huge page, in which case the pages before it have
+    /// already been changed.
+    #[allow(dead_code)]
+    pub fn protect(&mut self, range: Range<usize>, flags: MapFlags, asid: usize) -> Result<(), ()> {
+        if !flags.is_valid_leaf() {
+            warn!("invalid leaf flags {:?}", flags);
+            return Err(());
+        }
+        protect_level(self, levels() - 1, 0, &range, flags, asid)
+    }
+
+    /// Free a root table and every table below it, but not the frames that it
+    /// maps.
+    ///
+    /// The table must not be in use by any hart.
+    pub fn free(table: &'static mut PageTable, frame_allocator: &mut FrameAllocator) {
+        free_level(table, levels() - 1, frame_allocator);
+    }
+
+    /// Free a root table and the tables below it in the lower half of the
+    /// address space, leaving the kernel half that it shares with the kernel's
+    /// table.
+    ///
+    /// The table must not be in use by any hart.
+    pub fn free_user(table: &mut PageTable, frame_allocator: &mut FrameAllocator) {
+        for entry in table.inner[..KERNEL_HALF].iter_mut() {
+            if entry.valid() && entry.next_level() {
+                free_level(entry.as_table_mut().unwrap(), levels() - 2, frame_allocator);
+            }
+        }
+        frame_allocator.free(PageTable::phys(table), 0);
+    }
 
+    /// Give every root entry in the kernel half of the address space a table,
+    /// so that the entries never change and can be copied into other address
+    /// spaces with [`copy_kernel_half`](Self::copy_kernel_half).
+    ///
+    /// This should only be called on the kernel's root table.
+    pub fn preallocate_kernel_half(
+        &mut self,
+        frame_allocator: &mut FrameAllocator,
+    ) -> Result<(), ()> {
+        for entry in self.inner[KERNEL_HALF..].iter_mut() {
+            if !entry.valid() {
+                descend_table(entry, frame_allocator)?;
+            }
+        }
         Ok(())
     }
 
-    pub fn lookup(&mut self, virt: Sv39Virtual) -> Result<Sv39Physical, ()> {
-        let vpn_0 = virt.vpn(0) as usize;
-        let vpn_1 = virt.vpn(1) as usize;
-        let vpn_2 = virt.vpn(2) as usize;
+    /// Share the kernel half of the address space with the kernel's root table.
+    pub fn copy_kernel_half(&mut self, kernel: &PageTable) {
+        for (entry, kernel_entry) in self.inner[KERNEL_HALF..]
+            .iter_mut()
+            .zip(&kernel.inner[KERNEL_HALF..])
+        {
+            *entry = PageTableEntry(kernel_entry.0);
+        }
+    }
 
-        let entry = &mut self.inner[vpn_2];
-        let next = entry.as_table_mut().ok_or(())?;
-        let entry = &mut next.inner[vpn_1];
-        let next = entry.as_table_mut().ok_or(())?;
-        let entry = &mut next.inner[vpn_0];
+    /// Call `f` with every run of mappings in the table, in order of virtual
+    /// address. Runs of pages that are contiguous both virtually and
+    /// physically, with the same flags and page size, are merged into one.
+    ///
+    /// This should only be called on the root table.
+    pub fn mappings(&self, f: impl FnMut(&Mapping)) {
+        self.mappings_in(0..usize::MAX, f);
+    }
 
-        entry.as_physical_addr().ok_or(())
+    /// Call `f` with every run of mappings that overlaps a range of virtual
+    /// addresses, like [`mappings`](Self::mappings), without walking the
+    /// tables outside of the range.
+    pub fn mappings_in(&self, range: Range<usize>, mut f: impl FnMut(&Mapping)) {
+        let mut run = None;
+        mappings_level(self, levels() - 1, 0, &range, &mut run, &mut f);
+        if let Some(run) = run {
+            f(&run);
+        }
+    }
+
+    /// Get the root table that the current hart is using, if paging is on.
+    pub fn current() -> Option<&'static PageTable> {
+        let satp = csr::Satp::read();
+        if satp.mode() == 0 {
+            return None;
+        }
+        let addr = memory::phys_to_virt((satp.ppn() as usize) << PageSize::Normal.bits());
+        // # Safety
+        // The table is in use by the hart, so it must be a valid table.
+        Some(unsafe { &*(addr as *const PageTable) })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.iter().all(|entry| !entry.valid())
     }
 }
 
-fn descend_table<I: Iterator<Item = usize>>(
+/// A run of virtually and physically contiguous pages with the same flags.
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    /// Virtual address of the first page.
+    pub virt: usize,
+    /// Physical address of the first page.
+    pub phys: usize,
+    /// Size of the run in bytes.
+    pub len: usize,
+    pub flags: MapFlags,
+    pub page_size: PageSize,
+}
+
+/// Flags of a leaf mapping, in the same bit positions as in a page table
+/// entry.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapFlags(u64);
+
+#[allow(dead_code)]
+impl MapFlags {
+    pub const READ: Self = Self(1 << 1);
+    pub const WRITE: Self = Self(1 << 2);
+    pub const EXECUTE: Self = Self(1 << 3);
+    pub const USER: Self = Self(1 << 4);
+    pub const GLOBAL: Self = Self(1 << 5);
+    pub const ACCESSED: Self = Self(1 << 6);
+    pub const DIRTY: Self = Self(1 << 7);
+    /// Bits reserved for use by software.
+    pub const RSW_0: Self = Self(1 << 8);
+    pub const RSW_1: Self = Self(1 << 9);
+
+    /// Every bit that can be set.
+    const ALL: Self = Self(0x3FE | Self::MEMORY_TYPE_MASK);
+    const MEMORY_TYPE_SHIFT: u64 = 61;
+    const MEMORY_TYPE_MASK: u64 = 0b11 << Self::MEMORY_TYPE_SHIFT;
+
+    // The kernel doesn't track accessed and dirty pages, so they are set up
+    // front rather than relying on the hart to set them or fault
+    const KERNEL: Self = Self::GLOBAL.union(Self::ACCESSED).union(Self::DIRTY);
+    /// Read-only kernel data.
+    pub const KERNEL_R: Self = Self::KERNEL.union(Self::READ);
+    /// Kernel code.
+    pub const KERNEL_RX: Self = Self::KERNEL_R.union(Self::EXECUTE);
+    /// Writable kernel data.
+    pub const KERNEL_RW: Self = Self::KERNEL_R.union(Self::WRITE);
+    /// Device registers.
+    pub const KERNEL_MMIO: Self = Self::KERNEL_RW.with_memory_type(MemoryType::Io);
+    /// Buffers shared with devices that aren't cache coherent.
+    pub const KERNEL_DMA: Self = Self::KERNEL_RW.with_memory_type(MemoryType::Nc);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The flags that are set in `self` but not in `other`.
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn memory_type(self) -> MemoryType {
+        match (self.0 & Self::MEMORY_TYPE_MASK) >> Self::MEMORY_TYPE_SHIFT {
+            0 => MemoryType::Pma,
+            1 => MemoryType::Nc,
+            _ => MemoryType::Io,
+        }
+    }
+
+    /// Set the memory type, which is only used if the harts support Svpbmt.
+    pub const fn with_memory_type(self, memory_type: MemoryType) -> Self {
+        Self((self.0 & !Self::MEMORY_TYPE_MASK) | (memory_type as u64) << Self::MEMORY_TYPE_SHIFT)
+    }
+
+    /// Check whether a leaf can have these flags. Writable pages must also be
+    /// readable, and a leaf must allow some kind of access.
+    pub fn is_valid_leaf(self) -> bool {
+        (self.contains(Self::READ) || self.contains(Self::EXECUTE))
+            && (self.contains(Self::READ) || !self.contains(Self::WRITE))
+    }
+}
+
+impl BitOr for MapFlags {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        self.union(other)
+    }
+}
+
+impl BitOrAssign for MapFlags {
+    fn bitor_assign(&mut self, other: Self) {
+        *self = self.union(other);
+    }
+}
+
+/// Memory type of a mapping, overriding the physical memory attributes of the
+/// memory with the Svpbmt extension.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum MemoryType {
+    /// Use the physical memory attributes of the memory.
+    Pma = 0,
+    /// Non-cacheable, idempotent, weakly-ordered main memory.
+    Nc = 1,
+    /// Non-cacheable, non-idempotent, strongly-ordered I/O memory.
+    Io = 2,
+}
+
+/// Sign extend a virtual address so it is canonical.
+fn canonical(addr: usize) -> usize {
+    let unused_bits = usize::BITS as usize - mode().virt_bits();
+    (((addr << unused_bits) as isize) >> unused_bits) as usize
+}
+
+/// Walk a table at `level` that starts at the virtual address `base`, merging
+/// leaves into `run` and passing each finished run to `f`.
+fn mappings_level(
+    table: &PageTable,
+    level: usize,
+    base: usize,
+    range: &Range<usize>,
+    run: &mut Option<Mapping>,
+    f: &mut impl FnMut(&Mapping),
+) {
+    let size = PageSize::from_level(level).size();
+    for (index, entry) in table.inner.iter().enumerate() {
+        let start = canonical(base + index * size);
+        let end = start + (size - 1);
+        if !entry.valid() || end < range.start || start >= range.end {
+            continue;
+        }
+        if entry.next_level() {
+            mappings_level(entry.as_table().unwrap(), level - 1, start, range, run, f);
+            continue;
+        }
+
+        let leaf = leaf_range(entry, level, start);
+        let mapping = Mapping {
+            virt: start,
+            phys: entry.as_physical_addr().unwrap().0 as usize + (start - leaf.start),
+            len: size,
+            flags: entry.flags(),
+            page_size: entry.page_size(level),
+        };
+        match run {
+            Some(run)
+                if run.virt.wrapping_add(run.len) == mapping.virt
+                    && run.phys + run.len == mapping.phys
+                    && run.flags == mapping.flags
+                    && run.page_size == mapping.page_size =>
+            {
+                run.len += mapping.len;
+            }
+            _ => {
+                if let Some(run) = run.replace(mapping) {
+                    f(&run);
+                }
+            }
+        }
+    }
+}
+
+/// Range of virtual addresses mapped by the leaf that `entry` is part of, with
+/// an inclusive end, where the entry is at `level` and maps from `start`.
+fn leaf_range(entry: &PageTableEntry, level: usize, start: usize) -> Range<usize> {
+    let size = entry.page_size(level).size();
+    let leaf_start = start & !(size - 1);
+    leaf_start..leaf_start + (size - 1)
+}
+
+/// Flush a leaf entry from the TLB.
+fn flush_entry(addr: usize, asid: usize, global: bool) {
+    if global {
+        tlb::flush_global(addr);
+    } else {
+        tlb::flush(addr, asid);
+    }
+}
+
+/// Unmap the pages in `range` from a table at `level` that starts at the
+/// virtual address `base`.
+#[allow(clippy::too_many_arguments)]
+fn unmap_level(
+    table: &mut PageTable,
+    level: usize,
+    base: usize,
+    range: &Range<usize>,
+    asid: usize,
+    free_frames: bool,
+    tables_freed: &mut bool,
+    frame_allocator: &mut FrameAllocator,
+) -> Result<(), ()> {
+    let page_size = PageSize::from_level(level);
+    for (index, entry) in table.inner.iter_mut().enumerate() {
+        let start = canonical(base + index * page_size.size());
+        let end = start + (page_size.size() - 1);
+        if !entry.valid() || end < range.start || start >= range.end {
+            continue;
+        }
+
+        if entry.next_level() {
+            let child = entry.as_table_mut().unwrap();
+            unmap_level(
+                child,
+                level - 1,
+                start,
+                range,
+                asid,
+                free_frames,
+                tables_freed,
+                frame_allocator,
+            )?;
+            // Tables under the root in the kernel half are shared by every
+            // address space, so they are kept even when empty
+            let shared = level == levels() - 1 && index >= KERNEL_HALF;
+            if child.is_empty() && !shared {
+                *entry = PageTableEntry(0);
+                frame_allocator.free(PageTable::phys(child), 0);
+                *tables_freed = true;
+            }
+            continue;
+        }
+
+        let leaf = leaf_range(entry, level, start);
+        if leaf.start < range.start || leaf.end >= range.end {
+            warn!("unmapping part of a huge page at {:X}", leaf.start);
+            return Err(());
+        }
+        // The frames of a NAPOT page were allocated together, so they are
+        // freed together with its first entry
+        let leaf_size = entry.page_size(level);
+        let phys = entry.as_physical_addr().unwrap().0 as usize;
+        let global = entry.global();
+        *entry = PageTableEntry(0);
+        flush_entry(start, asid, global);
+        if free_frames && start == leaf.start {
+            frame_allocator.free(phys, leaf_size.order());
+        }
+    }
+    Ok(())
+}
+
+/// Free a table at `level` and every table below it.
+fn free_level(table: &'static mut PageTable, level: usize, frame_allocator: &mut FrameAllocator) {
+    if level > 0 {
+        for entry in table.inner.iter_mut() {
+            if entry.valid() && entry.next_level() {
+                free_level(entry.as_table_mut().unwrap(), level - 1, frame_allocator);
+            }
+        }
+    }
+    frame_allocator.free(PageTable::phys(table), 0);
+}
+
+/// Change the permissions of the pages in `range` in a table at `level` that
+/// starts at the virtual address `base`.
+fn protect_level(
+    table: &mut PageTable,
+    level: usize,
+    base: usize,
+    range: &Range<usize>,
+    flags: MapFlags,
+    asid: usize,
+) -> Result<(), ()> {
+    let page_size = PageSize::from_level(level);
+    for (index, entry) in table.inner.iter_mut().enumerate() {
+        let start = canonical(base + index * page_size.size());
+        let end = start + (page_size.size() - 1);
+        if !entry.valid() || end < range.start || start >= range.end {
+            continue;
+        }
+
+        if entry.next_level() {
+            let child = entry.as_table_mut().unwrap();
+            protect_level(child, level - 1, start, range, flags, asid)?;
+            continue;
+        }
+
+        let leaf = leaf_range(entry, level, start);
+        if leaf.start < range.start || leaf.end >= range.end {
+            warn!("changing flags of part of a huge page at {:X}", leaf.start);
+            return Err(());
+        }
+        // Global mappings are only flushed when no ASID is given
+        let global = entry.global();
+        let flags = if flags.memory_type() == MemoryType::Pma {
+            flags.with_memory_type(entry.flags().memory_type())
+        } else {
+            flags
+        };
+        entry.set_flags(flags);
+        flush_entry(start, asid, global || entry.global());
+    }
+    Ok(())
+}
+
+fn descend_table(
     entry: &mut PageTableEntry,
-    frame_allocator: &mut FrameAllocator<I>,
+    frame_allocator: &mut FrameAllocator,
 ) -> Result<&'static mut PageTable, ()> {
     if entry.valid() && !entry.next_level() {
-        // Entry already points to a mapping
+        // Entry is already a leaf, possibly a huge page covering this address
         warn!("entry points to a an existing mapping");
         return Err(());
     }
 
     if !entry.valid() {
         // Create new page table
-        let table = frame_allocator.next().unwrap();
-        //debug!("allocated table at {:p}", table);
+        let table = frame_allocator.alloc(0).ok_or(())?;
+        //debug!("allocated table at {:X}", table);
         let table = unsafe { PageTable::new(table) };
-        let table_phys = Sv39Physical(table as *mut PageTable as u64);
-        entry.set_ppn_0(table_phys.ppn_0());
-        entry.set_ppn_1(table_phys.ppn_1());
-        entry.set_ppn_2(table_phys.ppn_2());
+        entry.set_ppn(PageTable::ppn(table) as u64);
         entry.set_next_level();
         entry.set_valid(true);
     }
@@ -179,16 +894,19 @@ fn descend_table<I: Iterator<Item = usize>>(
     Ok(entry.as_table_mut().unwrap())
 }
 
+/// Index of the first root entry in the upper, kernel half of the address space.
+const KERNEL_HALF: usize = 256;
+
+/// Low bits of the PPN of an entry in a 64 KiB NAPOT page.
+const NAPOT_64K_PPN: u64 = 0b1000;
+
 bitfield! {
     pub struct PageTableEntry(u64);
     impl Debug;
 
     n, set_n: 63;
     pbmt, set_pbmt: 62, 61;
-    ppn_2, set_ppn_2: 53, 28;
-    ppn_1, set_ppn_1: 27, 19;
-    ppn_0, set_ppn_0: 18, 10;
-    ppn, set_ppn: 18, 10, 3;
+    ppn, set_ppn: 53, 10;
     rsw, set_rsw: 9, 8;
     dirty, set_dirty: 7;
     accessed, set_accessed: 6;
@@ -212,44 +930,91 @@ impl PageTableEntry {
         !self.r() && !self.w() && !self.x()
     }
 
+    /// Check whether the entry maps a page, rather than pointing at a table.
+    pub fn is_leaf(&self) -> bool {
+        self.valid() && !self.next_level()
+    }
+
+    /// Size of the page mapped by a leaf entry at the given level.
+    pub fn page_size(&self, level: usize) -> PageSize {
+        if self.n() {
+            PageSize::Napot64K
+        } else {
+            PageSize::from_level(level)
+        }
+    }
+
+    pub fn flags(&self) -> MapFlags {
+        MapFlags(self.0 & MapFlags::ALL.0)
+    }
+
+    /// Set the flags of a leaf, ignoring the memory type if the harts don't
+    /// support Svpbmt.
+    pub fn set_flags(&mut self, flags: MapFlags) {
+        let flags = if svpbmt() {
+            flags
+        } else {
+            flags.with_memory_type(MemoryType::Pma)
+        };
+        self.0 = (self.0 & !MapFlags::ALL.0) | flags.0;
+    }
+
+    /// Point a leaf at a different normal page.
+    pub fn set_physical_addr(&mut self, addr: PhysicalAddress) {
+        self.set_ppn(addr.ppn());
+    }
+
+    /// The raw value of the entry.
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
     /// Get the physical address the entry points to
-    pub fn as_physical_addr(&self) -> Option<Sv39Physical> {
+    pub fn as_physical_addr(&self) -> Option<PhysicalAddress> {
         if !self.valid() {
             return None;
         }
 
-        let mut phys_addr = Sv39Physical(0);
-        phys_addr.set_ppn_0(self.ppn_0());
-        phys_addr.set_ppn_1(self.ppn_1());
-        phys_addr.set_ppn_2(self.ppn_2());
+        let mut phys_addr = PhysicalAddress(0);
+        if self.n() {
+            phys_addr.set_ppn(self.ppn() & !(NAPOT_64K_PPN * 2 - 1));
+        } else {
+            phys_addr.set_ppn(self.ppn());
+        }
 
         Some(phys_addr)
     }
 
+    pub fn as_table(&self) -> Option<&'static PageTable> {
+        // # Safety
+        // The table is reached through the physmap, and is only read.
+        let addr = memory::phys_to_virt(self.as_physical_addr()?.0 as usize);
+        Some(unsafe { &*(addr as *const PageTable) })
+    }
+
     pub fn as_table_mut(&mut self) -> Option<&'static mut PageTable> {
         // # Safety
-        // This assumes that physical memory is identiy-mapped, and that the
-        // only way to get a mutable reference to the child page table is
-        // through the entry itself, and that no page table is referenced from
-        // multiple entries at any given time.
-        Some(unsafe { &mut *(self.as_physical_addr()?.0 as *mut PageTable) })
+        // This assumes that the only way to get a mutable reference to the
+        // child page table is through the entry itself, and that no page table
+        // is referenced from multiple entries at any given time. The table is
+        // reached through the physmap.
+        let addr = memory::phys_to_virt(self.as_physical_addr()?.0 as usize);
+        Some(unsafe { &mut *(addr as *mut PageTable) })
     }
 }
 
 bitfield! {
-    pub struct Sv39Virtual(u64);
+    pub struct VirtualAddress(u64);
     impl Debug;
 
-    vpn, set_vpn: 20, 12, 3;
+    vpn, set_vpn: 20, 12, 5;
     page_offset, set_page_offset: 11, 0;
 }
 
 bitfield! {
-    pub struct Sv39Physical(u64);
+    pub struct PhysicalAddress(u64);
     impl Debug;
 
-    ppn_2, set_ppn_2: 55, 30;
-    ppn_1, set_ppn_1: 29, 21;
-    ppn_0, set_ppn_0: 20, 12;
+    ppn, set_ppn: 55, 12;
     page_offset, set_page_offset: 11, 0;
 }
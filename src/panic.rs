@@ -1,6 +1,13 @@
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use log::error;
+use log::{error, Level};
+
+use crate::ptdump;
+
+/// Set once a hart has started panicking, so that a panic while dumping the
+/// page table doesn't try again.
+static PANICKED: AtomicBool = AtomicBool::new(false);
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -15,5 +22,9 @@ fn panic(info: &PanicInfo) -> ! {
         error!("  {}", location);
     }
 
+    if !PANICKED.swap(true, Ordering::Relaxed) {
+        ptdump::dump_current(Level::Error);
+    }
+
     crate::abort();
 }
@@ -0,0 +1,144 @@
+use core::arch::asm;
+use core::cell::{Cell, UnsafeCell};
+
+use alloc::sync::Arc;
+
+use crate::{address_space::AddressSpace, interrupts::TrapContext, smp::MAX_HARTS};
+
+/// What the hart is currently doing, as far as scheduling is concerned.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulerState {
+    Booting,
+    Idle,
+    Running,
+}
+
+/// Data that is local to a single hart.
+///
+/// A pointer to the current hart's block is kept in `tp` while running in the
+/// kernel, and in `sscratch` so the trap handler can find it.
+#[repr(C)]
+pub struct PerCpu {
+    /// Registers saved by the trap handler.
+    ///
+    /// This must be the first field, as the trap handler saves registers
+    /// relative to the address in sscratch.
+    trap_context: UnsafeCell<TrapContext>,
+    hart_id: usize,
+    kernel_stack_top: usize,
+    interrupt_depth: Cell<usize>,
+    scheduler_state: Cell<SchedulerState>,
+    /// The address space the hart has switched to, if not the kernel's.
+    address_space: Cell<Option<Arc<AddressSpace>>>,
+
+    /// The time at which the next timer interrupt is due.
+    pub timer_deadline: Cell<u64>,
+    /// The PLIC context used to take supervisor external interrupts.
+    pub plic_context: Cell<Option<usize>>,
+}
+
+impl PerCpu {
+    const fn new() -> Self {
+        Self {
+            trap_context: UnsafeCell::new(TrapContext::new()),
+            hart_id: 0,
+            kernel_stack_top: 0,
+            interrupt_depth: Cell::new(0),
+            scheduler_state: Cell::new(SchedulerState::Booting),
+            address_space: Cell::new(None),
+            timer_deadline: Cell::new(0),
+            plic_context: Cell::new(None),
+        }
+    }
+
+    /// Location the trap handler saves registers to.
+    pub fn trap_context(&self) -> *mut TrapContext {
+        self.trap_context.get()
+    }
+
+    pub fn hart_id(&self) -> usize {
+        self.hart_id
+    }
+
+    #[allow(dead_code)]
+    pub fn kernel_stack_top(&self) -> usize {
+        self.kernel_stack_top
+    }
+
+    /// Number of traps the hart is currently handling.
+    #[allow(dead_code)]
+    pub fn interrupt_depth(&self) -> usize {
+        self.interrupt_depth.get()
+    }
+
+    pub fn enter_interrupt(&self) {
+        self.interrupt_depth.set(self.interrupt_depth.get() + 1);
+    }
+
+    pub fn exit_interrupt(&self) {
+        self.interrupt_depth.set(self.interrupt_depth.get() - 1);
+    }
+
+    #[allow(dead_code)]
+    pub fn scheduler_state(&self) -> SchedulerState {
+        self.scheduler_state.get()
+    }
+
+    pub fn set_scheduler_state(&self, state: SchedulerState) {
+        self.scheduler_state.set(state);
+    }
+
+    /// The address space the hart is using, or None if it is only using the
+    /// kernel's page table.
+    pub fn address_space(&self) -> Option<Arc<AddressSpace>> {
+        let space = self.address_space.take();
+        self.address_space.set(space.clone());
+        space
+    }
+
+    /// Record the address space the hart is using, returning the previous one.
+    pub fn set_address_space(&self, space: Option<Arc<AddressSpace>>) -> Option<Arc<AddressSpace>> {
+        self.address_space.replace(space)
+    }
+}
+
+/// Storage for each hart's data.
+///
+/// Each hart only ever accesses its own entry, through tp.
+static mut CPUS: [PerCpu; MAX_HARTS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: PerCpu = PerCpu::new();
+    [EMPTY; MAX_HARTS]
+};
+
+/// Initialise the current hart's data and point tp at it.
+///
+/// This must be called once on each hart before anything uses [`current`].
+pub fn init(hart_id: usize, kernel_stack_top: usize) {
+    let cpu = unsafe { &mut *core::ptr::addr_of_mut!(CPUS[hart_id]) };
+    cpu.hart_id = hart_id;
+    cpu.kernel_stack_top = kernel_stack_top;
+
+    unsafe {
+        asm!("mv tp, {}", in(reg) cpu as *mut PerCpu);
+    }
+}
+
+/// Get the current hart's data.
+pub fn current() -> &'static PerCpu {
+    try_current().expect("per-cpu data not initialised")
+}
+
+/// Get the current hart's data, if it has been initialised.
+pub fn try_current() -> Option<&'static PerCpu> {
+    let tp: usize;
+    unsafe {
+        asm!("mv {}, tp", out(reg) tp);
+    }
+
+    // # Safety
+    // tp is only ever set to point at an entry in CPUS, and the entry is only
+    // accessed from the hart that owns it.
+    unsafe { (tp as *const PerCpu).as_ref() }
+}
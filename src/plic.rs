@@ -1,104 +1,189 @@
 use conquer_once::spin::OnceCell;
 use fdt::Fdt;
-use log::debug;
+use log::{debug, warn};
+
+use crate::{
+    mmio::{IoMapping, ReadOnly, ReadWrite, RegisterBlock, Reserved},
+    percpu,
+    smp::MAX_HARTS,
+};
 
 static PLIC: OnceCell<Plic> = OnceCell::uninit();
 
 struct Plic {
-    base_address: usize,
+    registers: IoMapping,
+    /// Number of contexts the PLIC has, across every hart and privilege mode.
+    context_count: usize,
+    /// The supervisor-mode context of each hart, if it has one.
+    contexts: [Option<usize>; MAX_HARTS],
 }
 
-const ENABLE_OFFSET: usize = 0x2000;
-const ENABLE_STRIDE: usize = 0x100;
-const ENABLE_S_MODE_OFFSET: usize = 0x80;
+/// Number of interrupt sources the PLIC's registers have room for.
+const SOURCES: usize = 1024;
+
+const PRIORITY_OFFSET: usize = 0;
 const PENDING_OFFSET: usize = 0x1000;
-const THRESHOLD_CLAIM_OFFSET: usize = 0x20_0000;
-const THRESHOLD_CLAIM_S_MODE_OFFSET: usize = 0x1000;
+const ENABLE_OFFSET: usize = 0x2000;
+const CLOCK_GATE_OFFSET: usize = 0x1F_F000;
+const CONTEXT_OFFSET: usize = 0x20_0000;
+
+/// Size of the PLIC's registers, if the FDT doesn't give it.
+const DEFAULT_SIZE: usize = 0x400_0000;
+
+/// A bit for each interrupt source, one register per 32 sources.
+type SourceBits<Register> = [Register; SOURCES / 32];
+
+/// The registers of a context that aren't shared with other contexts.
+#[repr(C)]
+struct ContextRegisters {
+    /// Interrupts at or below this priority aren't raised.
+    threshold: ReadWrite<u32>,
+    /// Reading claims the highest priority pending interrupt, and writing
+    /// completes one.
+    claim_complete: ReadWrite<u32>,
+    _reserved: Reserved<0xFF8>,
+}
+
+unsafe impl RegisterBlock for ContextRegisters {}
+
+/// The interrupt a context raises on its hart's local interrupt controller
+/// for supervisor-mode external interrupts.
+const SUPERVISOR_EXTERNAL_INTERRUPT: u32 = 9;
 
 /// Initialise the PLIC.
 pub fn init(fdt: &Fdt) {
     let plic_node = fdt.find_node("/soc/plic").unwrap();
-    let base_address = plic_node.reg().unwrap().next().unwrap().starting_address;
-    PLIC.init_once(|| Plic {
-        base_address: base_address as usize,
-    });
+    let region = plic_node.reg().unwrap().next().unwrap();
+    let size = region.size.unwrap_or(DEFAULT_SIZE);
 
-    // Parse available contexts from the FDT
+    // Parse available contexts from the FDT, each context is a pair of the
+    // hart's interrupt controller and the interrupt it raises on it
     let contexts = plic_node.property("interrupts-extended").unwrap();
     let contexts = contexts.value.chunks_exact(8).map(|bytes| {
         (
-            i32::from_be_bytes(bytes[..4].try_into().unwrap()),
-            i32::from_be_bytes(bytes[4..].try_into().unwrap()),
+            u32::from_be_bytes(bytes[..4].try_into().unwrap()),
+            u32::from_be_bytes(bytes[4..].try_into().unwrap()),
         )
     });
-    for context in contexts {
-        debug!("contexts {:?}", context);
+
+    let context_count = contexts.len();
+    let mut hart_contexts = [None; MAX_HARTS];
+    for (context, (phandle, interrupt)) in contexts.enumerate() {
+        if interrupt != SUPERVISOR_EXTERNAL_INTERRUPT {
+            continue;
+        }
+        match hart_for_interrupt_controller(fdt, phandle) {
+            Some(hart) if hart < MAX_HARTS => {
+                debug!("using plic context {} for hart {}", context, hart);
+                hart_contexts[hart] = Some(context);
+            }
+            _ => warn!("no hart found for plic context {}", context),
+        }
     }
 
-    // Allow all interrupts through the PLIC
-    set_threshold(0);
+    let registers =
+        IoMapping::new(region.starting_address as usize, size).expect("no room to map the plic");
+    PLIC.init_once(|| Plic {
+        registers,
+        context_count,
+        contexts: hart_contexts,
+    });
 
     // Enable the UART interrupt
     set_priority(0x0A, 1);
-    set_enable(0, 0x0A, true);
+}
+
+/// Initialise the PLIC for the current hart.
+pub fn init_hart() {
+    let cpu = percpu::current();
+    let context = PLIC.get().unwrap().contexts[cpu.hart_id()];
+    cpu.plic_context.set(context);
+
+    if context.is_none() {
+        warn!("hart {} has no supervisor plic context", cpu.hart_id());
+        return;
+    }
+
+    // Allow all interrupts through the PLIC
+    set_threshold(0);
+}
+
+/// Find the hart that owns the interrupt controller with the given phandle.
+fn hart_for_interrupt_controller(fdt: &Fdt, phandle: u32) -> Option<usize> {
+    fdt.find_all_nodes("/cpus/cpu")
+        .find(|cpu| {
+            cpu.children().any(|child| {
+                child.property("phandle").and_then(|p| p.as_usize()) == Some(phandle as usize)
+            })
+        })
+        .and_then(|cpu| Some(cpu.reg()?.next()?.starting_address as usize))
+}
+
+impl Plic {
+    /// Priority of each interrupt source, where source 0 doesn't exist.
+    fn priorities(&self) -> &[ReadWrite<u32>; SOURCES] {
+        self.registers.block(PRIORITY_OFFSET)
+    }
+
+    #[allow(dead_code)]
+    fn pending(&self) -> &SourceBits<ReadOnly<u32>> {
+        self.registers.block(PENDING_OFFSET)
+    }
+
+    /// The interrupts that are enabled for a context.
+    fn enables(&self, context: usize) -> &SourceBits<ReadWrite<u32>> {
+        &self
+            .registers
+            .blocks::<SourceBits<ReadWrite<u32>>>(ENABLE_OFFSET, self.context_count)[context]
+    }
+
+    fn context(&self, context: usize) -> &ContextRegisters {
+        &self
+            .registers
+            .blocks::<ContextRegisters>(CONTEXT_OFFSET, self.context_count)[context]
+    }
+
+    /// The registers of the current hart's context.
+    fn current_context(&self) -> &ContextRegisters {
+        let context = percpu::current()
+            .plic_context
+            .get()
+            .expect("hart has no plic context");
+        self.context(context)
+    }
 }
 
 pub fn set_priority(id: usize, priority: u8) {
-    let addr = (PLIC.get().unwrap().base_address + 4 * id) as *mut u32;
-    unsafe { addr.write_volatile(priority as u32) }
+    PLIC.get().unwrap().priorities()[id].write(priority as u32);
 }
 
 /// Check if a particular interrupt is pending.
 #[allow(dead_code)]
 pub fn interrupt_pending(id: usize) -> bool {
-    let plic_base = PLIC.get().unwrap().base_address + PENDING_OFFSET;
-    let offset = id / 32;
-    let bit_index = id % 32;
-    let addr = (plic_base + 4 * offset) as *mut u32;
-    let reg = unsafe { addr.read_volatile() };
-    reg & (1 << bit_index) != 0
+    let pending = PLIC.get().unwrap().pending()[id / 32].read();
+    pending & (1 << (id % 32)) != 0
 }
 
-/// Enable a particular interrupt.
+/// Enable a particular interrupt on a hart.
 pub fn set_enable(hart: usize, id: usize, enable: bool) {
-    let plic_base = PLIC.get().unwrap().base_address
-        + ENABLE_OFFSET
-        + ENABLE_STRIDE * hart
-        + ENABLE_S_MODE_OFFSET;
-    let offset = id / 32;
-    let bit_index = id % 32;
-    let addr = (plic_base + 4 * offset) as *mut u32;
-
-    let mask = 1 << bit_index;
-    let bit = (enable as u32) << bit_index;
-
-    unsafe {
-        let current = addr.read_volatile();
-
-        let new = (current & !mask) | bit;
-        addr.write_volatile(new);
-    }
+    let plic = PLIC.get().unwrap();
+    let context = plic.contexts[hart].expect("hart has no plic context");
+
+    let mask = 1 << (id % 32);
+    let bit = (enable as u32) << (id % 32);
+    plic.enables(context)[id / 32].modify(|current| (current & !mask) | bit);
 }
 
-/// Set the threshold required to trigger an interrupt.
+/// Set the threshold required to trigger an interrupt on the current hart.
 pub fn set_threshold(threshold: u8) {
-    let addr = (PLIC.get().unwrap().base_address
-        + THRESHOLD_CLAIM_OFFSET
-        + THRESHOLD_CLAIM_S_MODE_OFFSET) as *mut u32;
-
-    unsafe {
-        addr.write_volatile(threshold as u32);
-    }
+    let plic = PLIC.get().unwrap();
+    plic.current_context().threshold.write(threshold as u32);
 }
 
-/// Try to claim an interrupt.
+/// Try to claim an interrupt on the current hart.
 pub fn claim() -> Option<u32> {
-    let addr = (PLIC.get().unwrap().base_address
-        + THRESHOLD_CLAIM_OFFSET
-        + THRESHOLD_CLAIM_S_MODE_OFFSET
-        + 4) as *mut u32;
-
-    let id = unsafe { addr.read_volatile() };
+    let plic = PLIC.get().unwrap();
+    let id = plic.current_context().claim_complete.read();
 
     if id != 0 {
         Some(id)
@@ -107,16 +192,10 @@ pub fn claim() -> Option<u32> {
     }
 }
 
-/// Mark an interrupt as complete.
+/// Mark an interrupt claimed by the current hart as complete.
 pub fn complete(id: u32) {
-    let addr = (PLIC.get().unwrap().base_address
-        + THRESHOLD_CLAIM_OFFSET
-        + THRESHOLD_CLAIM_S_MODE_OFFSET
-        + 4) as *mut u32;
-
-    unsafe {
-        addr.write_volatile(id);
-    }
+    let plic = PLIC.get().unwrap();
+    plic.current_context().claim_complete.write(id);
 }
 
 /// Disable the Clock Gate on the PLIC.
@@ -124,8 +203,8 @@ pub fn complete(id: u32) {
 /// Documentation says this is needed, but isn't needed in QEMU. Leaving here for future platforms.
 #[allow(dead_code)]
 pub fn disable_clock_gate() {
-    let clock_gate_reg = (PLIC.get().unwrap().base_address + 0x1F_F000) as *mut u32;
-    unsafe {
-        clock_gate_reg.write_volatile(1);
-    }
+    let plic = PLIC.get().unwrap();
+    plic.registers
+        .block::<ReadWrite<u32>>(CLOCK_GATE_OFFSET)
+        .write(1);
 }
@@ -0,0 +1,144 @@
+use core::fmt;
+
+use log::{log, Level};
+
+use crate::paging::{MapFlags, Mapping, MemoryType, PageSize, PageTable, VirtualAddress};
+
+/// Log every mapping in a root table, merging contiguous runs, with one line
+/// for each run of the virtual range, physical range, size, flags and page
+/// size.
+pub fn dump(table: &PageTable, level: Level) {
+    log!(
+        level,
+        "{:<37} {:<25} {:>6} {:<11} page",
+        "virtual",
+        "physical",
+        "size",
+        "flags"
+    );
+    let mut total = 0;
+    table.mappings(|mapping| {
+        log!(level, "{}", Run(mapping));
+        total += mapping.len;
+    });
+    log!(level, "{} mapped", Size(total));
+}
+
+/// Log every mapping in the page table the current hart is using.
+pub fn dump_current(level: Level) {
+    match PageTable::current() {
+        Some(table) => dump(table, level),
+        None => log!(level, "paging is disabled"),
+    }
+}
+
+/// Log the entries the current hart's page table has for a virtual address,
+/// from the root down to the leaf or the first invalid entry.
+pub fn dump_walk(addr: usize, level: Level) {
+    let Some(table) = PageTable::current() else {
+        log!(level, "paging is disabled");
+        return;
+    };
+    log!(level, "page table walk of {:#018X}:", addr);
+    table.walk(VirtualAddress(addr as u64), |table_level, entry| {
+        let bits = entry.bits();
+        let Some(phys) = entry.as_physical_addr() else {
+            log!(level, "  level {}: {:#018X} invalid", table_level, bits);
+            return;
+        };
+        if entry.next_level() {
+            log!(
+                level,
+                "  level {}: {:#018X} table at {:#012X}",
+                table_level,
+                bits,
+                phys.0
+            );
+        } else {
+            log!(
+                level,
+                "  level {}: {:#018X} {} page at {:#012X} {}",
+                table_level,
+                bits,
+                Size(entry.page_size(table_level).size()),
+                phys.0,
+                Flags(entry.flags())
+            );
+        }
+    });
+}
+
+/// Formats a run of mappings as a line of the dump.
+struct Run<'a>(&'a Mapping);
+
+impl fmt::Display for Run<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mapping = self.0;
+        write!(
+            f,
+            "{:#018X}-{:#018X} {:#012X}-{:#012X} {:>6} {} {}",
+            mapping.virt,
+            mapping.virt.wrapping_add(mapping.len),
+            mapping.phys,
+            mapping.phys + mapping.len,
+            Size(mapping.len),
+            Flags(mapping.flags),
+            Size(mapping.page_size.size())
+        )
+    }
+}
+
+/// Formats flags like `rwxugad pma`, with a `-` for each flag that isn't set.
+struct Flags(MapFlags);
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = [
+            (MapFlags::READ, 'r'),
+            (MapFlags::WRITE, 'w'),
+            (MapFlags::EXECUTE, 'x'),
+            (MapFlags::USER, 'u'),
+            (MapFlags::GLOBAL, 'g'),
+            (MapFlags::ACCESSED, 'a'),
+            (MapFlags::DIRTY, 'd'),
+        ];
+        for (flag, c) in flags {
+            write!(f, "{}", if self.0.contains(flag) { c } else { '-' })?;
+        }
+        let memory_type = match self.0.memory_type() {
+            MemoryType::Pma => "pma",
+            MemoryType::Nc => "nc",
+            MemoryType::Io => "io",
+        };
+        write!(f, " {:<3}", memory_type)
+    }
+}
+
+/// Formats a size in bytes with the largest unit it is a whole multiple of.
+struct Size(usize);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units = [
+            (PageSize::Tera.size(), "T"),
+            (PageSize::Giga.size(), "G"),
+            (PageSize::Mega.size(), "M"),
+            (1024, "K"),
+        ];
+        let (unit, suffix) = units
+            .into_iter()
+            .find(|(unit, _)| self.0 != 0 && self.0 & (unit - 1) == 0)
+            .unwrap_or((1, "B"));
+        // Right align the number and suffix together, so the columns line up
+        // without needing the heap
+        let value = self.0 / unit;
+        let mut digits = 1;
+        let mut rest = value / 10;
+        while rest > 0 {
+            digits += 1;
+            rest /= 10;
+        }
+        let padding = f.width().unwrap_or(0).saturating_sub(digits + suffix.len());
+        write!(f, "{:padding$}{}{}", "", value, suffix, padding = padding)
+    }
+}
@@ -1 +1,2 @@
 pub mod instructions;
+pub mod tlb;
@@ -0,0 +1,60 @@
+use core::arch::asm;
+
+use log::warn;
+use sbi::HartMask;
+
+/// Flush the TLB entries for a virtual address in an address space.
+///
+/// This only affects leaf entries, so [`flush_asid`] must be used after
+/// changing or freeing a page table.
+pub fn flush(addr: usize, asid: usize) {
+    unsafe {
+        asm!("sfence.vma {}, {}", in(reg) addr, in(reg) asid);
+    }
+}
+
+/// Flush the TLB entries for a virtual address in every address space,
+/// including global mappings.
+pub fn flush_global(addr: usize) {
+    unsafe {
+        asm!("sfence.vma {}, x0", in(reg) addr);
+    }
+}
+
+/// Flush the TLB entries for every address in an address space.
+///
+/// Global mappings are not flushed.
+pub fn flush_asid(asid: usize) {
+    unsafe {
+        asm!("sfence.vma x0, {}", in(reg) asid);
+    }
+}
+
+/// Flush the entire TLB.
+pub fn flush_all() {
+    unsafe {
+        asm!("sfence.vma x0, x0");
+    }
+}
+
+/// Flush the TLB entries for every address in an address space on every hart.
+///
+/// Global mappings are not flushed.
+pub fn shootdown_asid(asid: usize) {
+    // A base of -1 selects every hart, and a size of -1 the whole address space
+    let all_harts = HartMask::new(usize::MAX);
+    if let Err(e) = sbi::rfence::remote_sfence_vma_asid(all_harts, 0, usize::MAX, asid) {
+        warn!("remote sfence.vma failed, only flushing this hart: {:?}", e);
+        flush_asid(asid);
+    }
+}
+
+/// Flush the entire TLB on every hart, including global mappings.
+pub fn shootdown_all() {
+    // A base of -1 selects every hart, and a size of -1 the whole address space
+    let all_harts = HartMask::new(usize::MAX);
+    if let Err(e) = sbi::rfence::remote_sfence_vma(all_harts, 0, usize::MAX) {
+        warn!("remote sfence.vma failed, only flushing this hart: {:?}", e);
+        flush_all();
+    }
+}
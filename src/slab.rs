@@ -0,0 +1,468 @@
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::{frame, heap_debug, interrupts, memory, paging::PageSize, percpu, smp::MAX_HARTS};
+
+/// Number of objects each hart can keep in a cache's magazine.
+const MAGAZINE_SIZE: usize = 32;
+/// A slab is made large enough to hold at least this many objects.
+const MIN_OBJECTS: usize = 8;
+
+/// A free object, linked into its slab's free list.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Bookkeeping at the start of each slab.
+struct SlabHeader {
+    /// First free object in the slab, or null if it is full.
+    free: *mut FreeObject,
+    /// Number of objects handed out from the slab.
+    in_use: usize,
+    /// Neighbours in the cache's list of partial slabs.
+    prev: *mut SlabHeader,
+    next: *mut SlabHeader,
+}
+
+/// Usage statistics for a slab cache.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub allocs: u64,
+    pub frees: u64,
+    /// Number of free objects, in slabs or in the harts' magazines.
+    pub free_objects: usize,
+    /// Number of slabs the cache holds.
+    pub slabs: usize,
+    /// Bytes of memory held by the slabs.
+    pub slab_bytes: usize,
+}
+
+/// The slabs of a cache, which are shared between harts.
+struct Slabs {
+    /// Slabs with both used and free objects.
+    partial: *mut SlabHeader,
+    /// A slab with no objects in use, kept so that a cache going back and forth
+    /// across a slab boundary doesn't allocate and free frames each time.
+    empty: *mut SlabHeader,
+    slabs: usize,
+    free_objects: usize,
+}
+
+// # Safety
+// The slabs are only reached through the cache's lock.
+unsafe impl Send for Slabs {}
+
+/// Objects a hart can allocate and free without taking the cache's lock.
+struct Magazine {
+    objects: UnsafeCell<[*mut u8; MAGAZINE_SIZE]>,
+    count: AtomicUsize,
+    allocs: AtomicU64,
+    frees: AtomicU64,
+}
+
+impl Magazine {
+    // Only used to initialise arrays, so it is never shared
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Self = Self {
+        objects: UnsafeCell::new([ptr::null_mut(); MAGAZINE_SIZE]),
+        count: AtomicUsize::new(0),
+        allocs: AtomicU64::new(0),
+        frees: AtomicU64::new(0),
+    };
+
+    /// Add one to a counter only the owning hart writes to.
+    fn bump(counter: &AtomicU64) {
+        counter.store(counter.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+    }
+}
+
+/// A cache of equally sized objects, carved from slabs of whole frames.
+///
+/// Each hart has a magazine of free objects that it allocates from and frees
+/// to with interrupts disabled, only taking the lock to move objects between
+/// its magazine and the slabs in batches. Slabs that become empty are returned
+/// to the frame allocator, apart from one kept as a spare.
+pub struct SlabCache {
+    /// Size of each object, a multiple of its alignment.
+    size: usize,
+    /// Slabs are `2^order` frames, aligned to their size.
+    order: usize,
+    /// Offset of the first object from the start of a slab.
+    offset: usize,
+    slabs: spin::Mutex<Slabs>,
+    magazines: [Magazine; MAX_HARTS],
+}
+
+// # Safety
+// A magazine is only touched by the hart it belongs to, with interrupts
+// disabled, so its objects are never accessed concurrently.
+unsafe impl Sync for SlabCache {}
+
+impl SlabCache {
+    /// Create a cache for objects of the given size and alignment.
+    pub const fn new(size: usize, align: usize) -> Self {
+        // Free objects hold a pointer
+        let align = if align > mem::align_of::<FreeObject>() {
+            align
+        } else {
+            mem::align_of::<FreeObject>()
+        };
+        let size = if size > mem::size_of::<FreeObject>() {
+            size
+        } else {
+            mem::size_of::<FreeObject>()
+        };
+        let size = memory::align_up(size, align);
+        let offset = memory::align_up(mem::size_of::<SlabHeader>(), align);
+
+        let mut order = 0;
+        while (PageSize::Normal.size() << order) - offset < size * MIN_OBJECTS {
+            order += 1;
+        }
+        assert!(order <= frame::MAX_ORDER, "slab object too large");
+
+        Self {
+            size,
+            order,
+            offset,
+            slabs: spin::Mutex::new(Slabs {
+                partial: ptr::null_mut(),
+                empty: ptr::null_mut(),
+                slabs: 0,
+                free_objects: 0,
+            }),
+            magazines: [Magazine::EMPTY; MAX_HARTS],
+        }
+    }
+
+    /// Size of the objects in the cache.
+    #[allow(dead_code)]
+    pub fn object_size(&self) -> usize {
+        self.size
+    }
+
+    fn slab_size(&self) -> usize {
+        PageSize::Normal.size() << self.order
+    }
+
+    /// Allocate an object, returning null if out of memory.
+    pub fn alloc(&self) -> *mut u8 {
+        interrupts::without_interrupts(|| {
+            let Some(magazine) = self.magazine() else {
+                return self.slabs.lock().alloc(self);
+            };
+            // # Safety
+            // Interrupts are disabled, so nothing else on this hart can use the
+            // magazine until this returns.
+            let objects = unsafe { &mut *magazine.objects.get() };
+            let mut count = magazine.count.load(Ordering::Relaxed);
+            if count == 0 {
+                // Refill half of the magazine, leaving room for frees
+                let mut slabs = self.slabs.lock();
+                while count < MAGAZINE_SIZE / 2 {
+                    let object = slabs.alloc(self);
+                    if object.is_null() {
+                        break;
+                    }
+                    objects[count] = object;
+                    count += 1;
+                }
+                if count == 0 {
+                    return ptr::null_mut();
+                }
+            }
+            count -= 1;
+            magazine.count.store(count, Ordering::Relaxed);
+            Magazine::bump(&magazine.allocs);
+            objects[count]
+        })
+    }
+
+    /// Free an object.
+    ///
+    /// # Safety
+    /// The object must have been allocated from this cache and not freed since.
+    pub unsafe fn free(&self, object: *mut u8) {
+        interrupts::without_interrupts(|| {
+            let Some(magazine) = self.magazine() else {
+                // # Safety
+                // The caller guarantees the object is from this cache.
+                unsafe { self.slabs.lock().free(self, object) };
+                return;
+            };
+            // # Safety
+            // Interrupts are disabled, so nothing else on this hart can use the
+            // magazine until this returns.
+            let objects = unsafe { &mut *magazine.objects.get() };
+            let mut count = magazine.count.load(Ordering::Relaxed);
+            if count == MAGAZINE_SIZE {
+                // Give the older half back to the slabs
+                let mut slabs = self.slabs.lock();
+                for &object in &objects[..MAGAZINE_SIZE / 2] {
+                    // # Safety
+                    // Objects only get into the magazine from this cache.
+                    unsafe { slabs.free(self, object) };
+                }
+                objects.copy_within(MAGAZINE_SIZE / 2.., 0);
+                count -= MAGAZINE_SIZE / 2;
+            }
+            objects[count] = object;
+            magazine.count.store(count + 1, Ordering::Relaxed);
+            Magazine::bump(&magazine.frees);
+        })
+    }
+
+    /// Get the cache's usage statistics.
+    pub fn stats(&self) -> CacheStats {
+        let (slabs, slab_free) = {
+            let slabs = self.slabs.lock();
+            (slabs.slabs, slabs.free_objects)
+        };
+        let mut stats = CacheStats {
+            slabs,
+            slab_bytes: slabs * self.slab_size(),
+            free_objects: slab_free,
+            ..CacheStats::default()
+        };
+        // Other harts may be using their magazines, so this is only a snapshot.
+        // Frees are read before allocs, so a block allocated and freed on
+        // different harts meanwhile isn't counted as freed but not allocated.
+        for magazine in &self.magazines {
+            stats.frees += magazine.frees.load(Ordering::Relaxed);
+            stats.free_objects += magazine.count.load(Ordering::Relaxed);
+        }
+        for magazine in &self.magazines {
+            stats.allocs += magazine.allocs.load(Ordering::Relaxed);
+        }
+        stats
+    }
+
+    /// The current hart's magazine, if per-CPU data is set up.
+    fn magazine(&self) -> Option<&Magazine> {
+        let hart_id = percpu::try_current()?.hart_id();
+        self.magazines.get(hart_id)
+    }
+}
+
+impl Slabs {
+    /// Take an object from a partial slab, or from a new slab if there are
+    /// none, returning null if out of memory.
+    fn alloc(&mut self, cache: &SlabCache) -> *mut u8 {
+        if self.partial.is_null() {
+            let slab = if self.empty.is_null() {
+                self.new_slab(cache)
+            } else {
+                mem::replace(&mut self.empty, ptr::null_mut())
+            };
+            if slab.is_null() {
+                return ptr::null_mut();
+            }
+            self.push_partial(slab);
+        }
+
+        // # Safety
+        // Slabs on the partial list are valid and have a free object.
+        unsafe {
+            let slab = self.partial;
+            let object = (*slab).free;
+            (*slab).free = (*object).next;
+            (*slab).in_use += 1;
+            self.free_objects -= 1;
+            if (*slab).free.is_null() {
+                self.remove_partial(slab);
+            }
+            object as *mut u8
+        }
+    }
+
+    /// Put an object back in its slab.
+    ///
+    /// # Safety
+    /// The object must have been allocated from the cache's slabs.
+    unsafe fn free(&mut self, cache: &SlabCache, object: *mut u8) {
+        let slab = (object as usize & !(cache.slab_size() - 1)) as *mut SlabHeader;
+        // # Safety
+        // Slabs are aligned to their size, so the header is at the start of the
+        // slab the object is in.
+        unsafe {
+            let was_full = (*slab).free.is_null();
+            let object = object as *mut FreeObject;
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+            self.free_objects += 1;
+
+            if was_full {
+                self.push_partial(slab);
+            }
+            if (*slab).in_use == 0 {
+                self.remove_partial(slab);
+                if self.empty.is_null() {
+                    self.empty = slab;
+                } else {
+                    self.free_slab(cache, slab);
+                }
+            }
+        }
+    }
+
+    /// Allocate frames for a slab and thread its objects onto its free list.
+    fn new_slab(&mut self, cache: &SlabCache) -> *mut SlabHeader {
+        let Some(frame) = frame::lock().alloc(cache.order) else {
+            return ptr::null_mut();
+        };
+        let start = memory::phys_to_virt(frame);
+        let objects = (cache.slab_size() - cache.offset) / cache.size;
+
+        // Link the objects in address order
+        let mut free = ptr::null_mut();
+        for i in (0..objects).rev() {
+            let object = (start + cache.offset + i * cache.size) as *mut FreeObject;
+            // # Safety
+            // The frames were just allocated and are reached through the physmap.
+            unsafe {
+                object.write(FreeObject { next: free });
+                heap_debug::on_new_block(object as *mut u8, cache.size);
+            }
+            free = object;
+        }
+        let slab = start as *mut SlabHeader;
+        // # Safety
+        // As above, and the header fits before the first object.
+        unsafe {
+            slab.write(SlabHeader {
+                free,
+                in_use: 0,
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+            })
+        };
+
+        self.slabs += 1;
+        self.free_objects += objects;
+        slab
+    }
+
+    /// Return an empty slab's frames to the frame allocator.
+    fn free_slab(&mut self, cache: &SlabCache, slab: *mut SlabHeader) {
+        self.slabs -= 1;
+        self.free_objects -= (cache.slab_size() - cache.offset) / cache.size;
+        frame::lock().free(memory::virt_to_phys(slab as usize), cache.order);
+    }
+
+    fn push_partial(&mut self, slab: *mut SlabHeader) {
+        // # Safety
+        // The slab and the list's head are valid slabs owned by the cache.
+        unsafe {
+            (*slab).prev = ptr::null_mut();
+            (*slab).next = self.partial;
+            if !self.partial.is_null() {
+                (*self.partial).prev = slab;
+            }
+        }
+        self.partial = slab;
+    }
+
+    fn remove_partial(&mut self, slab: *mut SlabHeader) {
+        // # Safety
+        // The slab is on the partial list, so it and its neighbours are valid.
+        unsafe {
+            let (prev, next) = ((*slab).prev, (*slab).next);
+            if prev.is_null() {
+                self.partial = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+            (*slab).prev = ptr::null_mut();
+            (*slab).next = ptr::null_mut();
+        }
+    }
+}
+
+/// A slab cache for values of a single type, for structures the kernel
+/// allocates and frees often, e.g.
+/// `static TASKS: ObjectCache<Task> = ObjectCache::new();`.
+#[allow(dead_code)]
+pub struct ObjectCache<T> {
+    cache: SlabCache,
+    _marker: PhantomData<T>,
+}
+
+// # Safety
+// The cache only hands out boxes, which move the values they hold between
+// harts like any owned value.
+unsafe impl<T: Send> Sync for ObjectCache<T> {}
+
+#[allow(dead_code)]
+impl<T> ObjectCache<T> {
+    pub const fn new() -> Self {
+        Self {
+            cache: SlabCache::new(mem::size_of::<T>(), mem::align_of::<T>()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Move a value into an object from the cache, returning it back if out of
+    /// memory.
+    pub fn alloc(&self, value: T) -> Result<CacheBox<'_, T>, T> {
+        let Some(ptr) = NonNull::new(self.cache.alloc() as *mut T) else {
+            return Err(value);
+        };
+        // # Safety
+        // The object is large enough and aligned for a `T`.
+        unsafe { ptr.as_ptr().write(value) };
+        Ok(CacheBox { cache: self, ptr })
+    }
+
+    /// Get the cache's usage statistics.
+    pub fn stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+}
+
+/// A value in an object of an [`ObjectCache`], which is freed when dropped.
+pub struct CacheBox<'a, T> {
+    cache: &'a ObjectCache<T>,
+    ptr: NonNull<T>,
+}
+
+// # Safety
+// The box owns its value.
+unsafe impl<T: Send> Send for CacheBox<'_, T> {}
+unsafe impl<T: Sync> Sync for CacheBox<'_, T> {}
+
+impl<T> Deref for CacheBox<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // # Safety
+        // The value lives as long as the box.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for CacheBox<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // # Safety
+        // The value lives as long as the box, which owns it.
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for CacheBox<'_, T> {
+    fn drop(&mut self) {
+        // # Safety
+        // The object came from this cache, and the value in it isn't used again.
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.cache.free(self.ptr.as_ptr() as *mut u8);
+        }
+    }
+}
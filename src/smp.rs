@@ -0,0 +1,72 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use fdt::Fdt;
+use log::{debug, info, warn};
+use sbi::hsm::HartStatus;
+
+use crate::memory;
+
+/// Number of harts that have stacks reserved for them in `boot.S`.
+pub const MAX_HARTS: usize = 8;
+/// Size of each hart's stack, must match `HART_STACK_SIZE` in `boot.S`.
+pub const HART_STACK_SIZE: usize = 1 << 14;
+
+/// Number of harts that have finished their initialisation.
+static ONLINE_HARTS: AtomicUsize = AtomicUsize::new(0);
+
+// This symbol is exposed by boot.S
+extern "C" {
+    fn _start_secondary();
+}
+
+/// Start every hart listed in the FDT other than the boot hart.
+///
+/// Returns once all of the started harts have come online.
+pub fn start_secondary_harts(boot_hart: usize, fdt: &Fdt) {
+    let mut started = 0;
+    for cpu in fdt.cpus() {
+        if let Some(status) = cpu.property("status").and_then(|p| p.as_str()) {
+            if status != "okay" {
+                debug!("skipping cpu with status {:?}", status);
+                continue;
+            }
+        }
+
+        for hart in cpu.ids().all() {
+            if hart == boot_hart {
+                continue;
+            }
+            if hart >= MAX_HARTS {
+                warn!("hart {} exceeds the maximum of {} harts", hart, MAX_HARTS);
+                continue;
+            }
+
+            match sbi::hsm::hart_status(hart) {
+                Ok(HartStatus::Stopped) => {}
+                status => {
+                    warn!("hart {} not startable, status {:?}", hart, status);
+                    continue;
+                }
+            }
+
+            debug!("starting hart {}", hart);
+            // The hart starts with paging disabled, so needs the physical address
+            let start_addr = memory::kernel_virt_to_phys(_start_secondary as *const () as usize);
+            match sbi::hsm::hart_start(hart, start_addr, 0) {
+                Ok(()) => started += 1,
+                Err(e) => warn!("failed to start hart {}: {:?}", hart, e),
+            }
+        }
+    }
+
+    // Wait for the secondary harts to finish their initialisation
+    while ONLINE_HARTS.load(Ordering::Acquire) < started {
+        core::hint::spin_loop();
+    }
+    info!("{} harts online", started + 1);
+}
+
+/// Mark the current hart as having finished its initialisation.
+pub fn hart_online() {
+    ONLINE_HARTS.fetch_add(1, Ordering::Release);
+}
@@ -0,0 +1,197 @@
+use alloc::collections::BTreeMap;
+use core::ptr::NonNull;
+
+use log::debug;
+
+use crate::{
+    frame,
+    memory::{self, KERNEL_ASID, VMALLOC_SIZE, VMALLOC_START},
+    paging::{MapFlags, PageSize, PhysicalAddress, VirtualAddress},
+    riscv::tlb,
+};
+
+const PAGE_SIZE: usize = PageSize::Normal.size();
+/// Size of the unmapped gap left after each area, so that running off the end
+/// of one faults rather than reaching the next.
+const GUARD_SIZE: usize = PAGE_SIZE;
+
+/// Areas allocated in the vmalloc region, by their start address.
+static AREAS: spin::Mutex<BTreeMap<usize, Area>> = spin::Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Copy)]
+struct Area {
+    size: usize,
+    /// Whether the area maps a device's memory rather than frames of its own.
+    io: bool,
+}
+
+/// Usage statistics for the vmalloc region.
+#[derive(Debug, Clone, Copy)]
+pub struct VmallocStats {
+    /// Number of areas allocated.
+    pub areas: usize,
+    /// Bytes of memory mapped for the areas.
+    pub bytes: usize,
+}
+
+/// Allocate `size` bytes of virtually contiguous kernel memory, backed by
+/// whatever frames are free, e.g. for large buffers and stacks.
+///
+/// The size is rounded up to whole pages, and the memory isn't zeroed. Returns
+/// None if the region or physical memory is exhausted.
+pub fn vmalloc(size: usize) -> Option<NonNull<u8>> {
+    if size == 0 {
+        return None;
+    }
+    let size = size.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1);
+    let start = reserve(size, false)?;
+
+    let mapped = map_pages(start, size);
+    memory::flush_new_kernel_mappings();
+    if mapped < size {
+        debug!("out of memory for a {} byte vmalloc area", size);
+        unmap(start, mapped, true);
+        AREAS.lock().remove(&start);
+        return None;
+    }
+    NonNull::new(start as *mut u8)
+}
+
+/// Free an area returned by [`vmalloc`], giving its frames back to the frame
+/// allocator.
+///
+/// # Safety
+/// The area must not be used afterwards.
+pub unsafe fn vfree(ptr: NonNull<u8>) {
+    let start = ptr.as_ptr() as usize;
+    let area = AREAS.lock().get(&start).copied();
+    let size = match area {
+        Some(Area { size, io: false }) => size,
+        _ => panic!("vfree of {:#X}, which vmalloc didn't return", start),
+    };
+    // Keep the range reserved until it is unmapped
+    unmap(start, size, true);
+    AREAS.lock().remove(&start);
+}
+
+/// Map `size` bytes of device memory at the physical address `phys` into the
+/// region with IO attributes, returning the address `phys` is mapped at.
+///
+/// Returns None if the region is exhausted or out of memory for page tables.
+pub fn ioremap(phys: usize, size: usize) -> Option<NonNull<u8>> {
+    let offset = phys & (PAGE_SIZE - 1);
+    let size = size.checked_add(offset)?.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1);
+    if size == 0 {
+        return None;
+    }
+    let start = reserve(size, true)?;
+
+    let result = memory::kernel_table().map_range(
+        VirtualAddress(start as u64),
+        PhysicalAddress((phys - offset) as u64),
+        size,
+        MapFlags::KERNEL_MMIO,
+        &mut frame::lock(),
+    );
+    memory::flush_new_kernel_mappings();
+    if result.is_err() {
+        debug!("out of memory to map {} bytes of device memory", size);
+        unmap(start, size, false);
+        AREAS.lock().remove(&start);
+        return None;
+    }
+    NonNull::new((start + offset) as *mut u8)
+}
+
+/// Unmap device memory mapped by [`ioremap`].
+///
+/// # Safety
+/// The device memory must not be used through the mapping afterwards.
+pub unsafe fn iounmap(ptr: NonNull<u8>) {
+    let start = ptr.as_ptr() as usize & !(PAGE_SIZE - 1);
+    let area = AREAS.lock().get(&start).copied();
+    let size = match area {
+        Some(Area { size, io: true }) => size,
+        _ => panic!("iounmap of {:#X}, which ioremap didn't return", start),
+    };
+    unmap(start, size, false);
+    AREAS.lock().remove(&start);
+}
+
+/// Get the vmalloc region's usage statistics, or None if another user of it
+/// holds its lock.
+pub fn try_stats() -> Option<VmallocStats> {
+    let areas = AREAS.try_lock()?;
+    Some(VmallocStats {
+        areas: areas.len(),
+        bytes: areas.values().map(|area| area.size).sum(),
+    })
+}
+
+/// Find room for an area of `size` bytes in the region and record it.
+fn reserve(size: usize, io: bool) -> Option<usize> {
+    let mut areas = AREAS.lock();
+    // The region starts with a guard, and each area is followed by one
+    let mut start = VMALLOC_START + GUARD_SIZE;
+    for (
+        &area,
+        &Area {
+            size: area_size, ..
+        },
+    ) in areas.iter()
+    {
+        if start + size + GUARD_SIZE <= area {
+            break;
+        }
+        start = area + area_size + GUARD_SIZE;
+    }
+    if start.checked_add(size)?.checked_add(GUARD_SIZE)? > VMALLOC_START + VMALLOC_SIZE {
+        return None;
+    }
+    areas.insert(start, Area { size, io });
+    Some(start)
+}
+
+/// Map frames from `start`, returning how much could be mapped before running
+/// out of memory.
+fn map_pages(start: usize, size: usize) -> usize {
+    let mut table = memory::kernel_table();
+    let mut frame_allocator = frame::lock();
+    let mut mapped = 0;
+    while mapped < size {
+        let Some(frame) = frame_allocator.alloc(0) else {
+            break;
+        };
+        let result = table.map(
+            VirtualAddress((start + mapped) as u64),
+            PhysicalAddress(frame as u64),
+            PageSize::Normal,
+            MapFlags::KERNEL_RW,
+            &mut frame_allocator,
+        );
+        if result.is_err() {
+            // There was no memory left for a page table
+            frame_allocator.free(frame, 0);
+            break;
+        }
+        mapped += PAGE_SIZE;
+    }
+    mapped
+}
+
+/// Unmap the first `size` bytes of an area, freeing the frames they map if
+/// `free_frames` is set.
+///
+/// This mustn't allocate, as it frees areas for the global allocator.
+fn unmap(start: usize, size: usize, free_frames: bool) {
+    memory::kernel_table()
+        .unmap(
+            start..start + size,
+            KERNEL_ASID,
+            free_frames,
+            &mut frame::lock(),
+        )
+        .expect("area only partly covers a huge page");
+    // Other harts may still have the pages or freed page tables cached
+    tlb::shootdown_all();
+}